@@ -0,0 +1,437 @@
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::espnow::{EspNowService, TimeMessage};
+use crate::storage::{StorageEntry, StorageService};
+use crate::topics::TAG_LEN;
+use crate::utils::{now_micros, MIN_VALID_TIME};
+use anyhow::{bail, Result};
+use base58::ToBase58;
+use blake2::digest::Mac;
+use esp_idf_svc::sntp::{EspSntp, SyncStatus};
+use futures_lite::future::or;
+use serde_json::Value;
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    rc::Rc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Strata as in NTP: lower is closer to a real reference, 16 means unsynced.
+const STRATUM_SNTP: u8 = 1;
+const STRATUM_MANUAL: u8 = 2;
+const MAX_STRATUM: u8 = 15;
+const STRATUM_UNSYNCED: u8 = 16;
+const SYNC_INTERVAL: Duration = Duration::from_secs(64);
+const UNSYNCED_INTERVAL: Duration = Duration::from_secs(8);
+const RESPONSE_WINDOW: Duration = Duration::from_secs(1);
+// A source not heard from for this long no longer counts.
+const HOLDOVER: Duration = Duration::from_secs(2 * 3600);
+// Smaller offsets are left alone rather than stepping the clock back and forth.
+const STEP_THRESHOLD_US: i64 = 50_000;
+const TIME_LABEL: &str = "liot time";
+
+// Everything in a response but the tag that covers it.
+fn signed_fields(server: &[u8], client: &[u8], stratum: u8, times: [u64; 3]) -> Result<Vec<u8>> {
+    Ok(postcard::to_allocvec(&(server, client, stratum, times))?)
+}
+
+fn set_time_micros(micros: i64) -> Result<()> {
+    let tv = esp_idf_sys::timeval {
+        tv_sec: (micros / 1_000_000) as _,
+        tv_usec: (micros % 1_000_000) as _,
+    };
+    if unsafe { esp_idf_sys::settimeofday(&tv, std::ptr::null()) } != 0 {
+        bail!("cannot set the system time");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    None,
+    Sntp,
+    Manual,
+    Mesh { server: String, stratum: u8 },
+}
+
+struct Sample {
+    server: String,
+    stratum: u8,
+    offset: i64,
+    delay: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub weekday: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+#[derive(Clone)]
+pub struct ClockService {
+    sntp: Rc<EspSntp>,
+    timezone: StorageEntry,
+    time: StorageEntry,
+    synced: StorageEntry,
+    set: StorageEntry,
+    stratum: StorageEntry,
+    source_name: StorageEntry,
+    offset: StorageEntry,
+    source: Rc<RefCell<Source>>,
+    last_sync: Rc<Cell<Option<Instant>>>,
+    pending: Rc<Cell<Option<u64>>>,
+    samples: Rc<RefCell<Vec<Sample>>>,
+}
+
+impl ClockService {
+    pub fn new(storage: &StorageService) -> Result<Self> {
+        let this = Self {
+            sntp: Rc::new(EspSntp::new_default()?),
+            timezone: storage.entry("clock_timezone"),
+            time: storage.entry("clock_time"),
+            synced: storage.entry("clock_synced"),
+            set: storage.entry("clock_set"),
+            stratum: storage.entry("clock_stratum"),
+            source_name: storage.entry("clock_source"),
+            offset: storage.entry("clock_offset"),
+            source: Rc::new(RefCell::new(Source::None)),
+            last_sync: Rc::new(Cell::new(None)),
+            pending: Rc::new(Cell::new(None)),
+            samples: Rc::new(RefCell::new(Vec::new())),
+        };
+        this.timezone
+            .get_or_init(|| Value::String(String::from("UTC0")));
+        this.set.set_unnotice(Value::from(0));
+        this.apply_timezone();
+        this.update_sync_state();
+        Ok(this)
+    }
+
+    fn current_stratum(&self) -> u8 {
+        match &*self.source.borrow() {
+            Source::None => STRATUM_UNSYNCED,
+            Source::Sntp => STRATUM_SNTP,
+            Source::Manual => STRATUM_MANUAL,
+            Source::Mesh { stratum, .. } => *stratum,
+        }
+    }
+
+    fn set_source(&self, source: Source) {
+        *self.source.borrow_mut() = source;
+        self.last_sync.set(Some(Instant::now()));
+        self.update_sync_state();
+    }
+
+    fn update_sync_state(&self) {
+        let name = match &*self.source.borrow() {
+            Source::None => String::from("none"),
+            Source::Sntp => String::from("sntp"),
+            Source::Manual => String::from("manual"),
+            Source::Mesh { server, .. } => format!("mesh {server}"),
+        };
+        self.source_name.set(Value::String(name));
+        self.stratum.set(Value::from(self.current_stratum()));
+    }
+
+    // SNTP reports completion once per sync; a manually set clock stays
+    // until something better comes along.
+    fn refresh_source(&self) {
+        if self.sntp.get_sync_status() == SyncStatus::Completed {
+            self.set_source(Source::Sntp);
+            return;
+        }
+        let expired = self
+            .last_sync
+            .get()
+            .is_some_and(|last| last.elapsed() > HOLDOVER);
+        let source = self.source.borrow().clone();
+        if expired && !matches!(source, Source::None | Source::Manual) {
+            println!("lost time source {source:?}");
+            *self.source.borrow_mut() = Source::None;
+            self.update_sync_state();
+        }
+    }
+
+    fn apply_timezone(&self) {
+        if let Some(tz) = self.timezone.get().as_str() {
+            std::env::set_var("TZ", tz);
+            unsafe { esp_idf_sys::tzset() };
+        }
+    }
+
+    pub fn now(&self) -> Option<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        (now >= MIN_VALID_TIME).then_some(now)
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.now().is_some()
+    }
+
+    pub fn local_time(&self, epoch: u64) -> LocalTime {
+        let time = epoch as esp_idf_sys::time_t;
+        let mut tm = esp_idf_sys::tm::default();
+        unsafe { esp_idf_sys::localtime_r(&time, &mut tm) };
+        LocalTime {
+            year: tm.tm_year + 1900,
+            month: tm.tm_mon as u32 + 1,
+            day: tm.tm_mday as u32,
+            weekday: tm.tm_wday as u32,
+            hour: tm.tm_hour as u32,
+            minute: tm.tm_min as u32,
+            second: tm.tm_sec as u32,
+        }
+    }
+
+    // One NTP-style exchange with every time server in range; the best
+    // answer is the lowest stratum, then the shortest round trip.
+    async fn poll_mesh(&self, espnow: &EspNowService) -> Result<()> {
+        let t1 = now_micros();
+        self.samples.borrow_mut().clear();
+        self.pending.set(Some(t1));
+        let request = TimeMessage::Request {
+            client: espnow.id().to_vec(),
+            t1,
+        };
+        espnow.send_time(request)?;
+        futures_timer::Delay::new(RESPONSE_WINDOW).await;
+        self.pending.set(None);
+        let best = self
+            .samples
+            .borrow_mut()
+            .drain(..)
+            .min_by_key(|s| (s.stratum, s.delay));
+        let Some(best) = best else {
+            return Ok(());
+        };
+        if best.stratum >= MAX_STRATUM || best.stratum + 1 > self.current_stratum() {
+            return Ok(());
+        }
+        if best.offset.abs() > STEP_THRESHOLD_US || !self.is_synced() {
+            set_time_micros(now_micros() as i64 + best.offset)?;
+            println!(
+                "clock stepped {} ms from {}",
+                best.offset / 1000,
+                best.server
+            );
+        }
+        self.offset.set(Value::from(best.offset / 1000));
+        self.set_source(Source::Mesh {
+            server: best.server,
+            stratum: best.stratum + 1,
+        });
+        Ok(())
+    }
+
+    fn handle_time(
+        &self,
+        espnow: &EspNowService,
+        message: TimeMessage,
+        received: u64,
+    ) -> Result<()> {
+        match message {
+            // Only paired clients can check an answer, so only they get one.
+            TimeMessage::Request { client, t1 } => {
+                let stratum = self.current_stratum();
+                if stratum >= MAX_STRATUM || !self.is_synced() {
+                    return Ok(());
+                }
+                let Some(mac) = espnow.peer_mac(&client, TIME_LABEL) else {
+                    return Ok(());
+                };
+                let server = espnow.id().to_vec();
+                let (t2, t3) = (received, now_micros());
+                let fields = signed_fields(&server, &client, stratum, [t1, t2, t3])?;
+                let mut tag = [0u8; TAG_LEN];
+                tag.copy_from_slice(&mac.chain_update(fields).finalize().into_bytes()[..TAG_LEN]);
+                espnow.send_time(TimeMessage::Response {
+                    server,
+                    client,
+                    stratum,
+                    t1,
+                    t2,
+                    t3,
+                    tag,
+                })?;
+            }
+            TimeMessage::Response {
+                server,
+                client,
+                stratum,
+                t1,
+                t2,
+                t3,
+                tag,
+            } => {
+                if client != espnow.id() || self.pending.get() != Some(t1) {
+                    return Ok(());
+                }
+                // Time is only taken from things the user paired with, and
+                // the tag shows it really came from one of them.
+                let fields = signed_fields(&server, &client, stratum, [t1, t2, t3])?;
+                let authentic = espnow.peer_mac(&server, TIME_LABEL).is_some_and(|mac| {
+                    mac.chain_update(fields).verify_truncated_left(&tag).is_ok()
+                });
+                if !authentic {
+                    return Ok(());
+                }
+                let server = server.to_base58();
+                let (t1, t2, t3, t4) = (t1 as i64, t2 as i64, t3 as i64, received as i64);
+                self.samples.borrow_mut().push(Sample {
+                    server,
+                    stratum,
+                    offset: ((t2 - t1) + (t3 - t4)) / 2,
+                    delay: (t4 - t1) - (t3 - t2),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn sync_handle(&self, espnow: &EspNowService) -> Result<()> {
+        let serve = async {
+            loop {
+                let (message, received) = espnow.next_time().await?;
+                self.handle_time(espnow, message, received)?;
+            }
+        };
+        let poll = async {
+            loop {
+                self.refresh_source();
+                if self.current_stratum() > STRATUM_MANUAL {
+                    self.poll_mesh(espnow).await?;
+                }
+                let interval = match self.current_stratum() {
+                    STRATUM_UNSYNCED => UNSYNCED_INTERVAL,
+                    _ => SYNC_INTERVAL,
+                };
+                futures_timer::Delay::new(interval).await;
+            }
+        };
+        or(serve, poll).await
+    }
+
+    pub async fn run_handle(&self) {
+        let future1 = async {
+            loop {
+                self.timezone.wait_new().await;
+                self.apply_timezone();
+            }
+        };
+        let set = async {
+            loop {
+                let Some(epoch) = self.set.wait_new().await.as_u64() else {
+                    continue;
+                };
+                if epoch < MIN_VALID_TIME {
+                    continue;
+                }
+                self.set.set_unnotice(Value::from(0));
+                match set_time_micros(epoch as i64 * 1_000_000) {
+                    Ok(()) => self.set_source(Source::Manual),
+                    Err(e) => println!("{e}"),
+                }
+            }
+        };
+        let future2 = async {
+            loop {
+                self.synced.set(Value::Bool(self.is_synced()));
+                if let Some(now) = self.now() {
+                    let t = self.local_time(now);
+                    self.time.set(Value::String(format!(
+                        "{:04}-{:02}-{:02} {:02}:{:02}",
+                        t.year, t.month, t.day, t.hour, t.minute
+                    )));
+                }
+                futures_timer::Delay::new(Duration::from_secs(5)).await;
+            }
+        };
+        or(future1, or(set, future2)).await
+    }
+}
+
+impl Schema for ClockService {
+    fn get_schema(&self) -> DataSchema {
+        let timezone = DataSchema {
+            id: self.timezone.get_key().to_string(),
+            title: Some(String::from("Timezone")),
+            description: Some(String::from("POSIX TZ string, e.g. ICT-7")),
+            local_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let time = DataSchema {
+            id: self.time.get_key().to_string(),
+            title: Some(String::from("Local time")),
+            read_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let synced = DataSchema {
+            id: self.synced.get_key().to_string(),
+            title: Some(String::from("Time synced")),
+            read_only: true,
+            detail: DetailDataSchema::Bool,
+            ..Default::default()
+        };
+        let set = DataSchema {
+            id: self.set.get_key().to_string(),
+            title: Some(String::from("Set time")),
+            description: Some(String::from("Seconds since the Unix epoch")),
+            write_only: true,
+            local_only: true,
+            detail: DetailDataSchema::Integer {
+                minimum: Some(MIN_VALID_TIME as i64),
+                maximum: None,
+            },
+            ..Default::default()
+        };
+        let stratum = DataSchema {
+            id: self.stratum.get_key().to_string(),
+            title: Some(String::from("Time stratum")),
+            read_only: true,
+            detail: DetailDataSchema::Integer {
+                minimum: Some(STRATUM_SNTP as i64),
+                maximum: Some(STRATUM_UNSYNCED as i64),
+            },
+            ..Default::default()
+        };
+        let source = DataSchema {
+            id: self.source_name.get_key().to_string(),
+            title: Some(String::from("Time source")),
+            read_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let offset = DataSchema {
+            id: self.offset.get_key().to_string(),
+            title: Some(String::from("Last time offset")),
+            unit: Some(String::from("ms")),
+            read_only: true,
+            detail: DetailDataSchema::Integer {
+                minimum: None,
+                maximum: None,
+            },
+            ..Default::default()
+        };
+        let mut map = BTreeMap::new();
+        map.insert(timezone.id.clone(), timezone);
+        map.insert(set.id.clone(), set);
+        map.insert(stratum.id.clone(), stratum);
+        map.insert(source.id.clone(), source);
+        map.insert(offset.id.clone(), offset);
+        map.insert(time.id.clone(), time);
+        map.insert(synced.id.clone(), synced);
+        DataSchema {
+            id: String::from("clock"),
+            title: Some(String::from("Clock")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use anyhow::Result;
+use async_channel::{unbounded, Receiver, Sender};
+use base58::ToBase58;
+
+#[async_trait::async_trait(?Send)]
+pub trait Connection {
+    fn is_init(&self) -> bool;
+    async fn remote_id(&self) -> Vec<u8>;
+    async fn remote_name(&self) -> String {
+        self.remote_id().await.to_base58()
+    }
+    async fn send(&self, data: &[u8]) -> anyhow::Result<()>;
+    // Resolves once the peer has acknowledged the message. Transports that
+    // cannot tell fall back to a plain send.
+    async fn send_reliable(&self, data: &[u8]) -> anyhow::Result<()> {
+        self.send(data).await
+    }
+    async fn recv(&self) -> anyhow::Result<Vec<u8>>;
+}
+
+// Two ends of an in-process link, for exercising protocol layers off-target.
+pub struct MemoryConnection {
+    init: bool,
+    remote_id: Vec<u8>,
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl MemoryConnection {
+    pub fn pair(a_id: &[u8], b_id: &[u8]) -> (Self, Self) {
+        let (a_tx, b_rx) = unbounded();
+        let (b_tx, a_rx) = unbounded();
+        let a = Self {
+            init: true,
+            remote_id: b_id.to_vec(),
+            tx: a_tx,
+            rx: a_rx,
+        };
+        let b = Self {
+            init: false,
+            remote_id: a_id.to_vec(),
+            tx: b_tx,
+            rx: b_rx,
+        };
+        (a, b)
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Connection for MemoryConnection {
+    fn is_init(&self) -> bool {
+        self.init
+    }
+    async fn remote_id(&self) -> Vec<u8> {
+        self.remote_id.clone()
+    }
+    async fn send(&self, data: &[u8]) -> Result<()> {
+        self.tx.send(data.to_vec()).await?;
+        Ok(())
+    }
+    async fn recv(&self) -> Result<Vec<u8>> {
+        Ok(self.rx.recv().await?)
+    }
+}
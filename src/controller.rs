@@ -1,29 +1,58 @@
-use crate::data_schema::Schema;
+pub use crate::connection::Connection;
 use crate::data_schema::ThingSchema;
-use crate::espnow::{self, EspNowService};
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::device::Device;
+use crate::espnow::protocol::{self, Capability, Json, Kind, Message, MessageHandler};
+use crate::espnow::{EspNowChannel, EspNowService, Publication, Refused};
+use crate::identity::thing_id;
+use crate::noise::SecureConnection;
+use crate::outbox::Outbox;
+use crate::pairing::Trust;
 use crate::storage::{StorageEntry, StorageService};
 use crate::wifi::WifiService;
-use base58::ToBase58;
+use anyhow::{anyhow, Result};
+use async_executor::LocalExecutor;
+use base58::{FromBase58, ToBase58};
+use futures_lite::future::or;
 use serde_json::Value;
-use std::{collections::BTreeMap, time::Duration};
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    rc::Rc,
+    time::Duration,
+};
 
-#[async_trait::async_trait(?Send)]
-pub trait Connection {
-    fn is_init(&self) -> bool;
-    async fn remote_id(&self) -> Vec<u8>;
-    async fn remote_name(&self) -> String {
-        self.remote_id().await.to_base58()
-    }
-    async fn send(&self, data: &[u8]) -> anyhow::Result<()>;
-    async fn recv(&self) -> anyhow::Result<Vec<u8>>;
+// Catches a peer that came back without being heard, e.g. when only its
+// answer to the last attempt was lost.
+const OUTBOX_RETRY: Duration = Duration::from_secs(60);
+const CAPABILITIES: [Capability; 6] = [
+    Capability::Schema,
+    Capability::Read,
+    Capability::Write,
+    Capability::Notify,
+    Capability::Invoke,
+    Capability::Relay,
+];
+
+struct Peer {
+    connection: Rc<dyn Connection>,
+    title: Option<String>,
+    capabilities: Vec<Capability>,
+    schema: Option<ThingSchema>,
 }
 
 pub struct Controller<'a> {
     id: String,
     wifi: WifiService<'a>,
-    devices: Vec<Box<dyn Schema>>,
+    devices: Vec<Box<dyn Device>>,
+    services: Vec<Box<dyn Schema>>,
     espnow: EspNowService,
+    storage: StorageService,
     title: StorageEntry,
+    peers: RefCell<BTreeMap<String, Peer>>,
+    static_key: Vec<u8>,
+    known_keys: RefCell<BTreeMap<Vec<u8>, Vec<u8>>>,
+    outbox: Outbox,
     //external_data: RefCell<BTreeMap<String, Value>>,
 }
 
@@ -32,25 +61,73 @@ impl<'a> Controller<'a> {
         name: &str,
         wifi: WifiService<'a>,
         storage: &StorageService,
-        device: Vec<Box<dyn Schema>>,
+        device: Vec<Box<dyn Device>>,
+        services: Vec<Box<dyn Schema>>,
         espnow: EspNowService,
+        static_key: &[u8],
     ) -> Self {
         Self {
             id: name.to_string(),
             wifi,
             devices: device,
+            services,
             espnow,
+            storage: storage.clone(),
             title: storage.entry("thing_title"),
+            peers: RefCell::new(BTreeMap::new()),
+            static_key: static_key.to_vec(),
+            known_keys: RefCell::new(BTreeMap::new()),
+            outbox: Outbox::new(storage),
             //external_data: RefCell::new(BTreeMap::new()),
         }
     }
 
+    pub fn espnow(&self) -> &EspNowService {
+        &self.espnow
+    }
+
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
     pub fn get_schema(&self) -> ThingSchema {
+        let mut schema = self.get_local_schema();
+        for (name, peer) in self.peers.borrow().iter() {
+            if let Some(peer_schema) = &peer.schema {
+                let prefix = format!("{name}/");
+                let properties = peer_schema
+                    .properties
+                    .values()
+                    .map(|p| p.with_prefix(&prefix))
+                    .map(|p| (p.id.clone(), p))
+                    .collect();
+                let mirror = DataSchema {
+                    id: name.clone(),
+                    title: peer_schema.title.clone(),
+                    detail: DetailDataSchema::Object { properties },
+                    ..Default::default()
+                };
+                schema.properties.insert(name.clone(), mirror);
+            }
+        }
+        schema
+    }
+
+    pub fn get_local_schema(&self) -> ThingSchema {
         let mut properties = BTreeMap::new();
         for device in &self.devices {
-            let device_schema = device.get_schema();
+            let mut device_schema = device.get_schema();
+            if let DetailDataSchema::Object { properties } = &mut device_schema.detail {
+                for field in self.lifecycle_schema(device.name()) {
+                    properties.insert(field.id.clone(), field);
+                }
+            }
             properties.insert(device_schema.id.clone(), device_schema);
         }
+        for service in &self.services {
+            let service_schema = service.get_schema();
+            properties.insert(service_schema.id.clone(), service_schema);
+        }
         let wifi_schema = self.wifi.get_schema();
         properties.insert(wifi_schema.id.clone(), wifi_schema);
         //let mut setting_properties = BTreeMap::new();
@@ -73,7 +150,7 @@ impl<'a> Controller<'a> {
         //}
 
         ThingSchema {
-            id: espnow::get_mac().to_base58(),
+            id: self.id.clone(),
             title: self
                 .title
                 .get_or_init(|| Value::String(String::from("Title")))
@@ -127,7 +204,411 @@ impl<'a> Controller<'a> {
     //        merge_value(dev_data, data);
     //    }
     //}
-    pub async fn run_handle(&self) {
+    fn lifecycle_schema(&self, name: &str) -> [DataSchema; 3] {
+        [
+            DataSchema {
+                id: format!("{name}_status"),
+                title: Some(format!("{name} status")),
+                description: self
+                    .storage
+                    .get(&format!("{name}_status"))
+                    .as_str()
+                    .map(String::from),
+                read_only: true,
+                detail: DetailDataSchema::String,
+                ..Default::default()
+            },
+            DataSchema {
+                id: format!("{name}_enabled"),
+                title: Some(format!("{name} enabled")),
+                local_only: true,
+                detail: DetailDataSchema::Bool,
+                ..Default::default()
+            },
+            DataSchema {
+                id: format!("{name}_identify"),
+                title: Some(format!("{name} identify")),
+                write_only: true,
+                detail: DetailDataSchema::Bool,
+                ..Default::default()
+            },
+        ]
+    }
+
+    async fn supervise(&self, device: &dyn Device) {
+        let name = device.name();
+        let status = self.storage.entry(&format!("{name}_status"));
+        let enabled = self.storage.entry(&format!("{name}_enabled"));
+        let identify = self.storage.entry(&format!("{name}_identify"));
+        let running = Cell::new(false);
+        let lifecycle = async {
+            loop {
+                if enabled.get_or_init(|| Value::Bool(true)).as_bool() == Some(false) {
+                    if let Err(e) = device.shutdown().await {
+                        println!("{name} shutdown failed: {e}");
+                    }
+                    status.set(Value::String(String::from("stopped")));
+                    enabled.wait_new().await;
+                    continue;
+                }
+                status.set(Value::String(device.health().to_string()));
+                running.set(true);
+                let stop = async {
+                    while enabled.wait_new().await.as_bool() != Some(false) {}
+                    Ok(())
+                };
+                let result = or(device.run(), stop).await;
+                running.set(false);
+                match result {
+                    Ok(()) if enabled.get().as_bool() != Some(false) => {
+                        status.set(Value::String(String::from("finished")));
+                        enabled.wait_new().await;
+                    }
+                    Ok(()) => (),
+                    Err(e) => {
+                        println!("{name} failed: {e}");
+                        status.set(Value::String(format!("failed: {e}")));
+                        futures_timer::Delay::new(Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        };
+        let observe = async {
+            loop {
+                futures_timer::Delay::new(Duration::from_secs(5)).await;
+                if running.get() {
+                    let health = Value::String(device.health().to_string());
+                    if status.get() != health {
+                        status.set(health);
+                    }
+                }
+            }
+        };
+        let identify = async {
+            loop {
+                if identify.wait_new().await.as_bool() == Some(true) {
+                    if let Err(e) = device.identify().await {
+                        println!("{name} identify failed: {e}");
+                    }
+                    identify.set_unnotice(Value::Bool(false));
+                }
+            }
+        };
+        or(lifecycle, or(observe, identify)).await
+    }
+
+    // Every subscriber of the topic, this thing included, applies the write.
+    pub async fn publish(&self, topic: &str, key: &str, value: Value) -> Result<()> {
+        let message = Message::Write {
+            key: key.to_string(),
+            value: Json(value.clone()),
+        };
+        self.espnow
+            .publish(topic, &protocol::encode(Kind::Event, message)?)?;
+        if self.espnow.topics().is_subscribed(topic) {
+            self.apply_write(key, value)?;
+        }
+        Ok(())
+    }
+
+    async fn handle_publication(&self, publication: Publication) {
+        let src = publication.src.to_base58();
+        let message = match protocol::decode(&publication.payload) {
+            Ok(envelope) => envelope.message,
+            Err(e) => {
+                println!("bad publication on {} from {src}: {e}", publication.topic);
+                return;
+            }
+        };
+        let message = match message {
+            Message::Write { ref key, .. }
+            | Message::Invoke {
+                action: ref key, ..
+            } if !self.espnow.topics().allows(key) => {
+                println!("{src} may not write {key} through {}", publication.topic);
+                return;
+            }
+            message @ (Message::Write { .. } | Message::Invoke { .. }) => message,
+            other => {
+                println!("unexpected publication from {src}: {other:?}");
+                return;
+            }
+        };
+        if let Message::Error(e) = self.handle_request(&src, message).await {
+            println!("publication on {} failed: {e}", publication.topic);
+        }
+    }
+
+    // Write-only fields on a peer are actions, so they are invoked rather
+    // than written. Local keys go through the same checks as remote writes.
+    pub async fn write(&self, key: &str, value: Value) -> Result<()> {
+        match key.split_once('/') {
+            Some((peer, key)) => {
+                let is_action = self.peer_field(peer, key).map(|f| f.write_only);
+                let message = match is_action {
+                    Some(true) => Message::Invoke {
+                        action: key.to_string(),
+                        input: Json(value.clone()),
+                    },
+                    _ => Message::Write {
+                        key: key.to_string(),
+                        value: Json(value.clone()),
+                    },
+                };
+                let capability = match message {
+                    Message::Invoke { .. } => Capability::Invoke,
+                    _ => Capability::Write,
+                };
+                match self.request(peer, capability, message).await {
+                    Ok(_) => Ok(()),
+                    Err(e) if e.is::<Refused>() => Err(e),
+                    // A paired peer that is away gets the write when it
+                    // connects again.
+                    Err(e) if self.espnow.pairing().is_paired(peer) => {
+                        println!("queued {key} for {peer}: {e}");
+                        self.outbox.push(peer, key, value, is_action == Some(true))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            None => self.apply_write(key, value),
+        }
+    }
+
+    pub async fn read(&self, key: &str) -> Result<Value> {
+        match key.split_once('/') {
+            Some((peer, key)) => {
+                let message = Message::Read {
+                    key: key.to_string(),
+                };
+                match self.request(peer, Capability::Read, message).await? {
+                    Message::Value(Json(value)) => Ok(value),
+                    other => Err(anyhow!("unexpected answer from {peer}: {other:?}")),
+                }
+            }
+            None => Ok(self.storage.get(key)),
+        }
+    }
+
+    fn peer_field(&self, peer: &str, key: &str) -> Option<DataSchema> {
+        let peers = self.peers.borrow();
+        peers.get(peer)?.schema.as_ref()?.find(key).cloned()
+    }
+
+    fn apply_write(&self, key: &str, value: Value) -> Result<()> {
+        let schema = self.get_local_schema();
+        let field = schema
+            .find(key)
+            .ok_or_else(|| anyhow!("unknown key {key}"))?;
+        field.validate(&value)?;
+        self.storage.set(key, value);
+        Ok(())
+    }
+
+    fn apply_invoke(&self, action: &str, input: Value) -> Result<()> {
+        match self.get_local_schema().find(action) {
+            Some(field) if field.write_only => self.apply_write(action, input),
+            Some(_) => Err(anyhow!("{action} is not an action")),
+            None => Err(anyhow!("unknown action {action}")),
+        }
+    }
+
+    fn read_local(&self, key: &str) -> Result<Value> {
+        match self.get_local_schema().find(key) {
+            Some(field) if field.secret => Err(anyhow!("{key} is not shared")),
+            Some(field) if !field.write_only => Ok(self.storage.get(key)),
+            Some(_) => Err(anyhow!("{key} is write only")),
+            None => Err(anyhow!("unknown key {key}")),
+        }
+    }
+
+    fn local_values(&self) -> BTreeMap<String, Value> {
+        self.get_local_schema()
+            .leaves()
+            .into_iter()
+            .filter(|field| !field.write_only && !field.secret)
+            .map(|field| (field.id.clone(), self.storage.get(&field.id)))
+            .collect()
+    }
+
+    async fn request(
+        &self,
+        peer: &str,
+        capability: Capability,
+        message: Message,
+    ) -> Result<Message> {
+        let connection = {
+            let peers = self.peers.borrow();
+            let entry = peers
+                .get(peer)
+                .ok_or_else(|| anyhow!("unknown peer {peer}"))?;
+            // Peers that have not said hello yet are given the benefit of
+            // the doubt.
+            if entry.title.is_some() && !entry.capabilities.contains(&capability) {
+                return Err(Refused(format!("peer {peer} does not support {capability:?}")).into());
+            }
+            entry.connection.clone()
+        };
+        self.espnow
+            .request(peer, connection.as_ref(), message)
+            .await
+    }
+
+    // Peers seen before are dialled with IK; if that fails the key is
+    // forgotten so the next attempt falls back to XX.
+    async fn handle_channel(&self, channel: EspNowChannel) {
+        let remote_id = channel.remote_id().await;
+        let known = self.known_keys.borrow().get(&remote_id).cloned();
+        let handshake = SecureConnection::handshake(channel, &self.static_key, known.as_deref());
+        let timeout = async {
+            futures_timer::Delay::new(Duration::from_secs(10)).await;
+            Err(anyhow!("handshake timed out"))
+        };
+        match or(handshake, timeout).await {
+            Ok(connection) if thing_id(connection.remote_static()) != remote_id => {
+                println!(
+                    "{} is not the thing it claimed to be",
+                    remote_id.to_base58()
+                );
+                self.known_keys.borrow_mut().remove(&remote_id);
+            }
+            Ok(connection) => {
+                let key = connection.remote_static().to_vec();
+                let id = thing_id(&key).to_base58();
+                let trusted = match self.espnow.pairing().check(&id, &key) {
+                    Trust::Paired => true,
+                    Trust::Mismatch => {
+                        println!("{id} presented a key other than the pinned one");
+                        false
+                    }
+                    Trust::Unknown => {
+                        match self
+                            .espnow
+                            .pairing()
+                            .offer(&id, &key, connection.handshake_hash())
+                        {
+                            Some(code) => {
+                                println!("{id} wants to pair, code {code}");
+                                self.espnow.pairing().wait_decision(&id).await
+                            }
+                            None => false,
+                        }
+                    }
+                };
+                if !trusted {
+                    self.known_keys.borrow_mut().remove(&remote_id);
+                    return;
+                }
+                self.known_keys.borrow_mut().insert(remote_id, key);
+                self.handle_connection(Rc::new(connection)).await
+            }
+            Err(e) => {
+                println!("handshake with {} failed: {e}", remote_id.to_base58());
+                self.known_keys.borrow_mut().remove(&remote_id);
+            }
+        }
+    }
+
+    // Stops at the first write that does not get through; the rest wait
+    // until the peer is heard from again or the next retry.
+    async fn flush_outbox(&self, peer: &str) {
+        for pending in self.outbox.pending(peer) {
+            let (capability, message) = match pending.action {
+                true => (
+                    Capability::Invoke,
+                    Message::Invoke {
+                        action: pending.key.clone(),
+                        input: Json(pending.value),
+                    },
+                ),
+                false => (
+                    Capability::Write,
+                    Message::Write {
+                        key: pending.key.clone(),
+                        value: Json(pending.value),
+                    },
+                ),
+            };
+            match self.request(peer, capability, message).await {
+                Ok(_) => self.outbox.delivered(peer, pending.id),
+                Err(e) if e.is::<Refused>() => self.outbox.refused(peer, pending.id, e.to_string()),
+                Err(e) => {
+                    self.outbox.failed(peer, pending.id, e.to_string());
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn handle_connection(&self, connection: Rc<dyn Connection>) {
+        let name = connection.remote_name().await;
+        self.peers.borrow_mut().insert(
+            name.clone(),
+            Peer {
+                connection: connection.clone(),
+                title: None,
+                capabilities: Vec::new(),
+                schema: None,
+            },
+        );
+        let hello = Message::Hello {
+            title: self.get_local_schema().title.unwrap_or_default(),
+            capabilities: CAPABILITIES.to_vec(),
+        };
+        self.espnow.notify(connection.as_ref(), hello).await.ok();
+        let announce = async {
+            loop {
+                let msg = Message::Schema(Json(self.get_local_schema()));
+                self.espnow.notify(connection.as_ref(), msg).await.ok();
+                futures_timer::Delay::new(Duration::from_secs(10)).await
+            }
+        };
+        // Changes are coalesced for a moment so a fade or a burst of writes
+        // goes out as one notification per key.
+        let notify = async {
+            let mut last = self.local_values();
+            loop {
+                self.storage.wait_changed().await;
+                futures_timer::Delay::new(Duration::from_millis(200)).await;
+                let values = self.local_values();
+                for (key, value) in &values {
+                    if last.get(key) != Some(value) {
+                        let msg = Message::Notify {
+                            key: key.clone(),
+                            value: Json(value.clone()),
+                        };
+                        self.espnow.notify(connection.as_ref(), msg).await.ok();
+                    }
+                }
+                last = values;
+            }
+        };
+        let receive = async {
+            if let Err(e) = self.espnow.dispatch(&name, connection.as_ref(), self).await {
+                println!("connection to {name} closed: {e}");
+            }
+        };
+        let unpaired = async {
+            while self.espnow.pairing().is_paired(&name) {
+                self.espnow.pairing().wait_changed().await;
+            }
+        };
+        // Sessions outlive a peer going to sleep, so the outbox is retried
+        // for as long as the session lasts, not only when it starts.
+        let flush = async {
+            let id = name.from_base58().unwrap_or_default();
+            loop {
+                self.flush_outbox(&name).await;
+                let retry = futures_timer::Delay::new(OUTBOX_RETRY);
+                or(self.espnow.wait_heard(&id), retry).await;
+            }
+        };
+        or(or(announce, notify), or(receive, or(unpaired, flush))).await;
+        self.peers.borrow_mut().remove(&name);
+    }
+
+    pub async fn run_handle(&self) -> Result<()> {
+        let ex = LocalExecutor::new();
         //let task1 = async {
         //    let dat = self.get_data();
         //    self.http_serve.set_data(dat).await;
@@ -169,11 +650,78 @@ impl<'a> Controller<'a> {
         //};
         let task4 = async {
             loop {
-                self.espnow.advertise().unwrap();
+                self.espnow.advertise()?;
                 futures_timer::Delay::new(Duration::from_secs(3)).await
             }
         };
-        task4.await;
+        let task5 = async {
+            loop {
+                let channel = self.espnow.next_channel().await?;
+                ex.spawn(self.handle_channel(channel)).detach();
+            }
+        };
+        let task6 = async {
+            loop {
+                let publication = self.espnow.next_publication().await?;
+                self.handle_publication(publication).await;
+            }
+        };
+        for device in &self.devices {
+            ex.spawn(self.supervise(device.as_ref())).detach();
+        }
+        ex.run(or(task4, or(task5, task6))).await
         //zip(zip(task1, task2), zip(task3, task4)).await;
     }
 }
+
+#[async_trait::async_trait(?Send)]
+impl<'a> MessageHandler for Controller<'a> {
+    async fn handle_request(&self, _peer: &str, message: Message) -> Message {
+        let result = match message {
+            Message::GetSchema => return Message::Schema(Json(self.get_local_schema())),
+            Message::Read { key } => self.read_local(&key),
+            Message::Write { key, value } => self
+                .get_local_schema()
+                .check_remote(&key)
+                .and_then(|()| self.apply_write(&key, value.0))
+                .map(|_| Value::Null),
+            Message::Invoke { action, input } => self
+                .get_local_schema()
+                .check_remote(&action)
+                .and_then(|()| self.apply_invoke(&action, input.0))
+                .map(|_| Value::Null),
+            other => Err(anyhow!("{other:?} is not a request")),
+        };
+        match result {
+            Ok(value) => Message::Value(Json(value)),
+            Err(e) => Message::Error(e.to_string()),
+        }
+    }
+
+    // Notified values are mirrored under the same "{peer}/{key}" ids the
+    // schema uses, so rules and /data see remote state like local state.
+    async fn handle_event(&self, peer: &str, message: Message) {
+        let mut peers = self.peers.borrow_mut();
+        let Some(entry) = peers.get_mut(peer) else {
+            return;
+        };
+        match message {
+            Message::Hello {
+                title,
+                capabilities,
+            } => {
+                entry.title = Some(title);
+                entry.capabilities = capabilities;
+            }
+            Message::Schema(Json(schema)) => entry.schema = Some(schema),
+            Message::Notify { key, value } => {
+                let known = entry.schema.as_ref().and_then(|s| s.find(&key)).is_some();
+                drop(peers);
+                if known {
+                    self.storage.set_volatile(&format!("{peer}/{key}"), value.0);
+                }
+            }
+            other => println!("unexpected event from {peer}: {other:?}"),
+        }
+    }
+}
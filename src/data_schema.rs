@@ -1,5 +1,6 @@
 use std::collections::BTreeMap;
 
+use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -28,6 +29,12 @@ pub struct DataSchema {
     pub one_of: Option<Vec<DataSchema>>,
     pub read_only: bool,
     pub write_only: bool,
+    // Writable here, e.g. over HTTP, but never by a peer.
+    #[serde(default)]
+    pub local_only: bool,
+    // Never read by or notified to a peer, which would mirror it.
+    #[serde(default)]
+    pub secret: bool,
     pub format: Option<String>,
     #[serde(flatten)]
     pub detail: DetailDataSchema,
@@ -76,6 +83,184 @@ pub enum DetailDataSchema {
     },
 }
 
+impl ThingSchema {
+    pub fn find(&self, key: &str) -> Option<&DataSchema> {
+        self.properties.values().find_map(|p| p.find(key))
+    }
+
+    // Every field that holds a value, i.e. all but the grouping objects.
+    pub fn leaves(&self) -> Vec<&DataSchema> {
+        let mut leaves = Vec::new();
+        for property in self.properties.values() {
+            property.collect_leaves(&mut leaves);
+        }
+        leaves
+    }
+
+    // Peers may not change who is trusted or how this thing is set up.
+    pub fn check_remote(&self, key: &str) -> Result<()> {
+        match self.find(key) {
+            Some(field) if field.local_only => bail!("{key} can only be set locally"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl DataSchema {
+    pub fn find(&self, key: &str) -> Option<&DataSchema> {
+        if self.id == key {
+            return Some(self);
+        }
+        match &self.detail {
+            DetailDataSchema::Object { properties } => {
+                properties.values().find_map(|p| p.find(key))
+            }
+            _ => None,
+        }
+    }
+
+    fn collect_leaves<'s>(&'s self, leaves: &mut Vec<&'s DataSchema>) {
+        match &self.detail {
+            DetailDataSchema::Object { properties } => {
+                for property in properties.values() {
+                    property.collect_leaves(leaves);
+                }
+            }
+            _ => leaves.push(self),
+        }
+    }
+
+    pub fn with_prefix(&self, prefix: &str) -> DataSchema {
+        let mut schema = self.clone();
+        schema.id = format!("{prefix}{}", self.id);
+        if let DetailDataSchema::Object { properties } = &self.detail {
+            schema.detail = DetailDataSchema::Object {
+                properties: properties
+                    .values()
+                    .map(|p| p.with_prefix(prefix))
+                    .map(|p| (p.id.clone(), p))
+                    .collect(),
+            };
+        }
+        schema
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<()> {
+        if self.read_only {
+            bail!("{} is read only", self.id);
+        }
+        match (&self.detail, value) {
+            (DetailDataSchema::Bool, Value::Bool(_)) => Ok(()),
+            (DetailDataSchema::String, Value::String(_)) => Ok(()),
+            (DetailDataSchema::Null, Value::Null) => Ok(()),
+            (DetailDataSchema::Number { minimum, maximum }, Value::Number(n)) => {
+                let n = n.as_f64().unwrap_or_default();
+                match (minimum, maximum) {
+                    (Some(min), _) if n < *min => bail!("{} must be at least {min}", self.id),
+                    (_, Some(max)) if n > *max => bail!("{} must be at most {max}", self.id),
+                    _ => Ok(()),
+                }
+            }
+            (DetailDataSchema::Integer { minimum, maximum }, Value::Number(n)) => {
+                let n = n
+                    .as_i64()
+                    .ok_or_else(|| anyhow!("{} expects an integer", self.id))?;
+                match (minimum, maximum) {
+                    (Some(min), _) if n < *min => bail!("{} must be at least {min}", self.id),
+                    (_, Some(max)) if n > *max => bail!("{} must be at most {max}", self.id),
+                    _ => Ok(()),
+                }
+            }
+            // An object without properties is free-form, a map of any keys.
+            (DetailDataSchema::Object { properties }, Value::Object(_))
+                if properties.is_empty() =>
+            {
+                Ok(())
+            }
+            (DetailDataSchema::Object { properties }, Value::Object(map)) => {
+                for (key, value) in map {
+                    properties
+                        .get(key)
+                        .ok_or_else(|| anyhow!("{} has no field {key}", self.id))?
+                        .validate(value)?;
+                }
+                Ok(())
+            }
+            (
+                DetailDataSchema::Array {
+                    items,
+                    min_items,
+                    max_items,
+                },
+                Value::Array(values),
+            ) => {
+                if values.len() < *min_items as usize || values.len() > *max_items as usize {
+                    bail!("{} expects {min_items} to {max_items} items", self.id);
+                }
+                // A single item schema holds for every item, several are
+                // matched up by position.
+                match items.as_slice() {
+                    [item] => values.iter().try_for_each(|value| item.validate(value)),
+                    items => items
+                        .iter()
+                        .zip(values)
+                        .try_for_each(|(item, value)| item.validate(value)),
+                }
+            }
+            _ => bail!("{} has wrong type", self.id),
+        }
+    }
+}
+
 pub trait Schema {
     fn get_schema(&self) -> DataSchema;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Shaped like the Wi-Fi settings next to an ordinary device field.
+    fn thing() -> ThingSchema {
+        let ssid = DataSchema {
+            id: String::from("wifi_config_ssid"),
+            local_only: true,
+            secret: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let state = DataSchema {
+            id: String::from("module-1_state"),
+            detail: DetailDataSchema::Bool,
+            ..Default::default()
+        };
+        let wifi = DataSchema {
+            id: String::from("wifi"),
+            detail: DetailDataSchema::Object {
+                properties: BTreeMap::from([(ssid.id.clone(), ssid)]),
+            },
+            ..Default::default()
+        };
+        ThingSchema {
+            id: String::from("thing"),
+            properties: BTreeMap::from([(wifi.id.clone(), wifi), (state.id.clone(), state)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn refuses_remote_writes_to_local_only_fields() {
+        let thing = thing();
+        assert!(thing.check_remote("wifi_config_ssid").is_err());
+        assert!(thing.check_remote("module-1_state").is_ok());
+    }
+
+    #[test]
+    fn keeps_flags_across_the_wire() {
+        let thing = thing();
+        let json = serde_json::to_string(&thing).unwrap();
+        let thing: ThingSchema = serde_json::from_str(&json).unwrap();
+        let ssid = thing.find("wifi_config_ssid").unwrap();
+        assert!(ssid.local_only && ssid.secret);
+    }
+}
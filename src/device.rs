@@ -1,2 +1,37 @@
+pub mod factory;
 pub mod pwm_device;
 pub mod sensor_device;
+
+use crate::data_schema::Schema;
+use anyhow::Result;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Ok,
+    Warning(String),
+    Error(String),
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Health::Ok => write!(f, "running"),
+            Health::Warning(msg) => write!(f, "warning: {msg}"),
+            Health::Error(msg) => write!(f, "error: {msg}"),
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+pub trait Device: Schema {
+    fn name(&self) -> &str;
+    async fn run(&self) -> Result<()>;
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+    fn health(&self) -> Health {
+        Health::Ok
+    }
+    async fn identify(&self) -> Result<()>;
+}
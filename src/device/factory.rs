@@ -0,0 +1,326 @@
+use super::pwm_device::PWMDevice;
+use super::sensor_device::SensorDevice;
+use super::Device;
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::storage::{StorageEntry, StorageService};
+use anyhow::{anyhow, bail, Result};
+use esp_idf_hal::{
+    gpio::{AnyIOPin, AnyOutputPin, PinDriver, Pull},
+    ledc::{
+        config::TimerConfig, LedcDriver, LedcTimerDriver, Resolution, CHANNEL0, CHANNEL1, CHANNEL2,
+        CHANNEL3, CHANNEL4, CHANNEL5, LEDC, TIMER0, TIMER1, TIMER2, TIMER3,
+    },
+    units::Hertz,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+const LEDC_TIMERS: u8 = 4;
+const LEDC_CHANNELS: u8 = 6;
+
+// GPIO11-17 are wired to the SPI flash on the ESP32-C3.
+fn is_usable_pin(pin: i32) -> bool {
+    matches!(pin, 0..=10 | 18..=21)
+}
+
+fn default_frequency() -> u32 {
+    2000
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorPull {
+    #[default]
+    Floating,
+    Up,
+    Down,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeviceConfig {
+    Pwm {
+        name: String,
+        pin: i32,
+        timer: u8,
+        channel: u8,
+        #[serde(default = "default_frequency")]
+        frequency: u32,
+    },
+    Sensor {
+        name: String,
+        pin: i32,
+        #[serde(default)]
+        pull: SensorPull,
+    },
+}
+
+impl DeviceConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            DeviceConfig::Pwm { name, .. } | DeviceConfig::Sensor { name, .. } => name,
+        }
+    }
+    pub fn pin(&self) -> i32 {
+        match self {
+            DeviceConfig::Pwm { pin, .. } | DeviceConfig::Sensor { pin, .. } => *pin,
+        }
+    }
+}
+
+pub fn default_manifest() -> Vec<DeviceConfig> {
+    vec![
+        DeviceConfig::Pwm {
+            name: String::from("module-1"),
+            pin: 3,
+            timer: 0,
+            channel: 0,
+            frequency: default_frequency(),
+        },
+        DeviceConfig::Pwm {
+            name: String::from("module-2"),
+            pin: 4,
+            timer: 1,
+            channel: 1,
+            frequency: default_frequency(),
+        },
+    ]
+}
+
+pub fn validate(manifest: &[DeviceConfig]) -> Result<()> {
+    let mut names = BTreeSet::new();
+    let mut pins = BTreeSet::new();
+    let mut timers = BTreeSet::new();
+    let mut channels = BTreeSet::new();
+    for config in manifest {
+        let name = config.name();
+        if name.is_empty() || name.contains('/') {
+            bail!("invalid device name {name:?}");
+        }
+        if !names.insert(name) {
+            bail!("duplicate device name {name}");
+        }
+        if !is_usable_pin(config.pin()) {
+            bail!("{name}: gpio{} is not usable", config.pin());
+        }
+        if !pins.insert(config.pin()) {
+            bail!("{name}: gpio{} is already used", config.pin());
+        }
+        if let DeviceConfig::Pwm {
+            timer,
+            channel,
+            frequency,
+            ..
+        } = config
+        {
+            if *timer >= LEDC_TIMERS || !timers.insert(*timer) {
+                bail!("{name}: ledc timer {timer} is invalid or already used");
+            }
+            if *channel >= LEDC_CHANNELS || !channels.insert(*channel) {
+                bail!("{name}: ledc channel {channel} is invalid or already used");
+            }
+            if *frequency == 0 {
+                bail!("{name}: frequency must not be 0");
+            }
+        }
+    }
+    Ok(())
+}
+
+type Timers = (
+    Option<TIMER0>,
+    Option<TIMER1>,
+    Option<TIMER2>,
+    Option<TIMER3>,
+);
+type Channels = (
+    Option<CHANNEL0>,
+    Option<CHANNEL1>,
+    Option<CHANNEL2>,
+    Option<CHANNEL3>,
+    Option<CHANNEL4>,
+    Option<CHANNEL5>,
+);
+
+pub struct DeviceFactory {
+    storage: StorageService,
+    timers: Timers,
+    channels: Channels,
+}
+
+impl DeviceFactory {
+    pub fn new(ledc: LEDC, storage: &StorageService) -> Self {
+        Self {
+            storage: storage.clone(),
+            timers: (
+                Some(ledc.timer0),
+                Some(ledc.timer1),
+                Some(ledc.timer2),
+                Some(ledc.timer3),
+            ),
+            channels: (
+                Some(ledc.channel0),
+                Some(ledc.channel1),
+                Some(ledc.channel2),
+                Some(ledc.channel3),
+                Some(ledc.channel4),
+                Some(ledc.channel5),
+            ),
+        }
+    }
+
+    fn timer_driver(
+        &mut self,
+        timer: u8,
+        config: &TimerConfig,
+    ) -> Result<LedcTimerDriver<'static>> {
+        let taken = || anyhow!("ledc timer {timer} is already taken");
+        let driver = match timer {
+            0 => LedcTimerDriver::new(self.timers.0.take().ok_or_else(taken)?, config)?,
+            1 => LedcTimerDriver::new(self.timers.1.take().ok_or_else(taken)?, config)?,
+            2 => LedcTimerDriver::new(self.timers.2.take().ok_or_else(taken)?, config)?,
+            3 => LedcTimerDriver::new(self.timers.3.take().ok_or_else(taken)?, config)?,
+            _ => bail!("no ledc timer {timer}"),
+        };
+        Ok(driver)
+    }
+
+    fn channel_driver(
+        &mut self,
+        channel: u8,
+        timer: LedcTimerDriver<'static>,
+        pin: AnyOutputPin,
+        config: &TimerConfig,
+    ) -> Result<LedcDriver<'static>> {
+        let taken = || anyhow!("ledc channel {channel} is already taken");
+        let c = &mut self.channels;
+        let driver = match channel {
+            0 => LedcDriver::new(c.0.take().ok_or_else(taken)?, timer, pin, config)?,
+            1 => LedcDriver::new(c.1.take().ok_or_else(taken)?, timer, pin, config)?,
+            2 => LedcDriver::new(c.2.take().ok_or_else(taken)?, timer, pin, config)?,
+            3 => LedcDriver::new(c.3.take().ok_or_else(taken)?, timer, pin, config)?,
+            4 => LedcDriver::new(c.4.take().ok_or_else(taken)?, timer, pin, config)?,
+            5 => LedcDriver::new(c.5.take().ok_or_else(taken)?, timer, pin, config)?,
+            _ => bail!("no ledc channel {channel}"),
+        };
+        Ok(driver)
+    }
+
+    // Pins are created by number, so the manifest must have passed `validate`
+    // to make sure no two devices claim the same one.
+    pub fn build(&mut self, config: &DeviceConfig) -> Result<Box<dyn Device>> {
+        match config {
+            DeviceConfig::Pwm {
+                name,
+                pin,
+                timer,
+                channel,
+                frequency,
+            } => {
+                let timer_config = TimerConfig::new()
+                    .frequency(Hertz(*frequency))
+                    .resolution(Resolution::Bits10);
+                let timer = self.timer_driver(*timer, &timer_config)?;
+                let pin = unsafe { AnyOutputPin::new(*pin) };
+                let channel = self.channel_driver(*channel, timer, pin, &timer_config)?;
+                Ok(Box::new(PWMDevice::new(
+                    name,
+                    channel,
+                    self.storage.clone(),
+                )))
+            }
+            DeviceConfig::Sensor { name, pin, pull } => {
+                let mut driver = PinDriver::input(unsafe { AnyIOPin::new(*pin) })?;
+                driver.set_pull(match pull {
+                    SensorPull::Floating => Pull::Floating,
+                    SensorPull::Up => Pull::Up,
+                    SensorPull::Down => Pull::Down,
+                })?;
+                Ok(Box::new(SensorDevice::new(name, driver, &self.storage)))
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DeviceManifest {
+    manifest: StorageEntry,
+    status: StorageEntry,
+}
+
+impl DeviceManifest {
+    pub fn new(storage: &StorageService) -> Self {
+        Self {
+            manifest: storage.entry("device_manifest"),
+            status: storage.entry("device_manifest_status"),
+        }
+    }
+
+    pub fn get(&self) -> Value {
+        self.manifest
+            .get_or_init(|| serde_json::to_value(default_manifest()).unwrap_or_default())
+    }
+
+    pub fn parse(value: Value) -> Result<Vec<DeviceConfig>> {
+        let manifest: Vec<DeviceConfig> = serde_json::from_value(value)?;
+        validate(&manifest)?;
+        Ok(manifest)
+    }
+
+    pub fn set(&self, value: Value) -> Result<()> {
+        Self::parse(value.clone())?;
+        self.manifest.set(value);
+        self.status
+            .set(Value::String(String::from("saved, restart to apply")));
+        Ok(())
+    }
+
+    // An invalid manifest leaves every pin untouched so WiFi and HTTP stay
+    // reachable to fix it.
+    pub fn build_devices(&self, factory: &mut DeviceFactory) -> Vec<Box<dyn Device>> {
+        let manifest = match Self::parse(self.get()) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                println!("invalid device manifest, starting in safe mode: {e}");
+                self.status.set(Value::String(format!("safe mode: {e}")));
+                return Vec::new();
+            }
+        };
+        let mut devices = Vec::new();
+        let mut errors = Vec::new();
+        for config in &manifest {
+            match factory.build(config) {
+                Ok(device) => devices.push(device),
+                Err(e) => errors.push(format!("{}: {e}", config.name())),
+            }
+        }
+        let status = if errors.is_empty() {
+            String::from("ok")
+        } else {
+            errors.join("; ")
+        };
+        self.status.set(Value::String(status));
+        devices
+    }
+}
+
+impl Schema for DeviceManifest {
+    fn get_schema(&self) -> DataSchema {
+        let status = DataSchema {
+            id: self.status.get_key().to_string(),
+            title: Some(String::from("Device manifest")),
+            read_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let mut map = BTreeMap::new();
+        map.insert(status.id.clone(), status);
+        DataSchema {
+            id: String::from("devices"),
+            title: Some(String::from("Devices")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
+    }
+}
@@ -1,14 +1,11 @@
+use super::Device;
 use crate::data_schema::Schema;
 use crate::{
     data_schema::{DataSchema, DetailDataSchema},
     storage::{StorageEntry, StorageService},
 };
-use esp_idf_hal::{
-    gpio::OutputPin,
-    ledc::{config::TimerConfig, LedcChannel, LedcDriver, LedcTimer, LedcTimerDriver, Resolution},
-    peripheral::Peripheral,
-    units::Hertz,
-};
+use anyhow::Result;
+use esp_idf_hal::ledc::LedcDriver;
 use futures_lite::future::or;
 use serde_json::{Number, Value};
 use std::time::Duration;
@@ -29,18 +26,7 @@ pub struct PWMDevice<'a> {
 }
 
 impl<'a> PWMDevice<'a> {
-    pub fn new<T: LedcTimer, C: LedcChannel>(
-        name: &str,
-        timer: impl Peripheral<P = T> + 'a,
-        channel: impl Peripheral<P = C> + 'a,
-        pin: impl Peripheral<P = impl OutputPin> + 'a,
-        storage: StorageService,
-    ) -> Self {
-        let timer_config = TimerConfig::new()
-            .frequency(Hertz(2000))
-            .resolution(Resolution::Bits10);
-        let timer = LedcTimerDriver::new(timer, &timer_config).unwrap();
-        let channel = LedcDriver::new(channel, timer, pin, &timer_config).unwrap();
+    pub fn new(name: &str, channel: LedcDriver<'a>, storage: StorageService) -> Self {
         let max = channel.get_max_duty();
 
         let ret = Self {
@@ -60,10 +46,9 @@ impl<'a> PWMDevice<'a> {
             .get_or_init(|| serde_json::Value::Number(Number::from(0)));
         ret.state.set(serde_json::Value::Bool(val == 0));
 
-        ret.dev
-            .borrow_mut()
-            .set_duty(val.as_u64().unwrap() as u32)
-            .ok();
+        if let Some(duty) = val.as_u64() {
+            ret.dev.borrow_mut().set_duty(duty as u32).ok();
+        }
         ret
     }
     pub async fn run_handle(&self) {
@@ -132,6 +117,35 @@ impl<'a> PWMDevice<'a> {
     }
 }
 
+#[async_trait::async_trait(?Send)]
+impl<'a> Device for PWMDevice<'a> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    async fn run(&self) -> Result<()> {
+        if let Some(duty) = self.duty.get().as_u64() {
+            self.dev.borrow_mut().set_duty(duty as u32)?;
+        }
+        self.run_handle().await;
+        Ok(())
+    }
+    async fn shutdown(&self) -> Result<()> {
+        self.dev.borrow_mut().set_duty(self.min)?;
+        Ok(())
+    }
+    async fn identify(&self) -> Result<()> {
+        let current = self.dev.borrow().get_duty();
+        for _ in 0..3 {
+            self.dev.borrow_mut().set_duty(self.max)?;
+            futures_timer::Delay::new(Duration::from_millis(300)).await;
+            self.dev.borrow_mut().set_duty(self.min)?;
+            futures_timer::Delay::new(Duration::from_millis(300)).await;
+        }
+        self.dev.borrow_mut().set_duty(current)?;
+        Ok(())
+    }
+}
+
 impl<'a> Schema for PWMDevice<'a> {
     fn get_schema(&self) -> DataSchema {
         let name = &self.name;
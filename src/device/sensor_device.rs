@@ -1,11 +1,15 @@
+use super::Device;
 use crate::{
     data_schema::{DataSchema, DetailDataSchema, Schema},
     storage::{StorageEntry, StorageService},
 };
+use anyhow::{anyhow, bail, Result};
 use embedded_hal::digital::InputPin;
+use serde_json::Value;
 use std::{cell::RefCell, collections::BTreeMap, rc::Rc, time::Duration};
 
 pub struct SensorDevice<T: InputPin> {
+    name: String,
     title_state: StorageEntry,
     device: Rc<RefCell<T>>,
     state: StorageEntry,
@@ -14,43 +18,87 @@ pub struct SensorDevice<T: InputPin> {
 impl<T: InputPin> SensorDevice<T> {
     pub fn new(name: &str, device: T, storage: &StorageService) -> Self {
         Self {
+            name: name.to_string(),
             device: Rc::new(RefCell::new(device)),
             title_state: storage.entry(&format!("{name}_title_state")),
             state: storage.entry(&format!("{name}_state")),
         }
     }
 
-    async fn wait_new_state(&self) -> bool {
-        let state = self.device.borrow().is_high().unwrap();
-        while self.device.borrow().is_high().unwrap() == state {
+    async fn wait_new_state(&self) -> Result<bool> {
+        let read = || {
+            self.device
+                .borrow()
+                .is_high()
+                .map_err(|e| anyhow!("{}: failed to read pin: {e:?}", self.name))
+        };
+        let state = read()?;
+        while read()? == state {
             futures_timer::Delay::new(Duration::from_millis(50)).await;
         }
-        !state
+        Ok(!state)
     }
-    pub async fn run_handle(&self) {
+    pub async fn run_handle(&self) -> Result<()> {
         loop {
-            let state = self.wait_new_state().await;
+            let state = self.wait_new_state().await?;
             self.state.set(serde_json::Value::Bool(state));
         }
     }
 }
-//impl<T: InputPin> Schema for SensorDevice<T> {
-//    fn get_schema(&self) -> DataSchema {
-//        let state = DataSchema {
-//            id: self.state.get_key().to_string(),
-//            title: self.title_state.get().as_str().map(String::from),
-//            detail: DetailDataSchema::Bool,
-//            ..Default::default()
-//        };
-//        let edit_title = DataSchema {
-//            id: self.title_state.get_key().to_string(),
-//            title: Some(String::from("Change state title")),
-//            detail: DetailDataSchema::String,
-//            ..Default::default()
-//        };
-//        let mut properties = BTreeMap::new();
-//        properties.insert(state.id.to_owned(), state);
-//        properties.insert(edit_title.id.to_owned(), edit_title);
-//        properties
-//    }
-//}
+
+#[async_trait::async_trait(?Send)]
+impl<T: InputPin> Device for SensorDevice<T> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    async fn run(&self) -> Result<()> {
+        self.run_handle().await
+    }
+    async fn identify(&self) -> Result<()> {
+        bail!("{} has no indicator", self.name)
+    }
+}
+
+impl<T: InputPin> Clone for SensorDevice<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            title_state: self.title_state.clone(),
+            device: self.device.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: InputPin> Schema for SensorDevice<T> {
+    fn get_schema(&self) -> DataSchema {
+        let name = &self.name;
+        let state = DataSchema {
+            id: self.state.get_key().to_string(),
+            title: self
+                .title_state
+                .get_or_init(|| Value::String(format!("{name} state")))
+                .as_str()
+                .map(String::from),
+            read_only: true,
+            detail: DetailDataSchema::Bool,
+            description: self.state.get().as_bool().map(|b| b.to_string()),
+            ..Default::default()
+        };
+        let edit_title = DataSchema {
+            id: self.title_state.get_key().to_string(),
+            title: Some(String::from("Change state title")),
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let mut properties = BTreeMap::new();
+        properties.insert(state.id.to_owned(), state);
+        properties.insert(edit_title.id.to_owned(), edit_title);
+        DataSchema {
+            id: self.name.clone(),
+            title: Some(self.name.clone()),
+            detail: DetailDataSchema::Object { properties },
+            ..Default::default()
+        }
+    }
+}
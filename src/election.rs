@@ -0,0 +1,213 @@
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::espnow::{Candidacy, EspNowService};
+use crate::storage::{StorageEntry, StorageService};
+use crate::wifi::WifiService;
+use anyhow::Result;
+use base58::ToBase58;
+use futures_lite::future::or;
+use serde_json::Value;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+const BEACON_INTERVAL: Duration = Duration::from_secs(3);
+// A candidate missing this many beacons is gone and the rest re-elect.
+const CANDIDATE_TIMEOUT: Duration = Duration::from_secs(10);
+// Outlives the candidates themselves, so a recorded beacon cannot bring a
+// gone node back; bounded in case a key holder makes up ids.
+const MAX_SEEN: usize = 64;
+
+const ROLE_COORDINATOR: &str = "coordinator";
+const ROLE_MEMBER: &str = "member";
+
+type Candidates = BTreeMap<Vec<u8>, (Candidacy, Instant)>;
+
+// Every node floods its candidacy and independently picks the best one it
+// can hear, so nodes that hear the same set agree without a round of votes.
+// Candidacies are tagged under the network key, so without one a node
+// neither stands nor listens and leads only itself.
+// Only the coordinator serves the aggregated web UI, and internet facing
+// bridges should likewise start and stop on `wait_role`.
+#[derive(Clone)]
+pub struct ElectionService {
+    priority: StorageEntry,
+    mains: StorageEntry,
+    leader: StorageEntry,
+    role: StorageEntry,
+    candidates: Rc<RefCell<Candidates>>,
+    last_seq: Rc<RefCell<BTreeMap<Vec<u8>, u32>>>,
+}
+
+impl ElectionService {
+    pub fn new(storage: &StorageService) -> Self {
+        let this = Self {
+            priority: storage.entry("election_priority"),
+            mains: storage.entry("election_mains"),
+            leader: storage.entry("election_leader"),
+            role: storage.entry("election_role"),
+            candidates: Rc::new(RefCell::new(BTreeMap::new())),
+            last_seq: Rc::new(RefCell::new(BTreeMap::new())),
+        };
+        this.priority.get_or_init(|| Value::from(0));
+        this.mains.get_or_init(|| Value::Bool(true));
+        // Nobody leads until the first round has run.
+        this.leader.set_unnotice(Value::String(String::new()));
+        this.role
+            .set_unnotice(Value::String(String::from(ROLE_MEMBER)));
+        this
+    }
+
+    pub fn is_coordinator(&self) -> bool {
+        self.role.get().as_str() == Some(ROLE_COORDINATOR)
+    }
+
+    pub fn leader(&self) -> Option<String> {
+        self.leader
+            .get()
+            .as_str()
+            .filter(|leader| !leader.is_empty())
+            .map(String::from)
+    }
+
+    pub async fn wait_role(&self) -> bool {
+        self.role.wait_new().await;
+        self.is_coordinator()
+    }
+
+    fn candidacy(&self, wifi: &WifiService<'_>) -> Candidacy {
+        Candidacy {
+            sta_connected: wifi.is_connected().unwrap_or(false),
+            mains_powered: self.mains.get().as_bool().unwrap_or(true),
+            priority: self.priority.get().as_u64().unwrap_or(0).min(255) as u8,
+        }
+    }
+
+    // Ties on rank fall to the larger id, as `EspNowChannel::is_initializer`
+    // does for connections.
+    fn elect(&self, id: &[u8], own: Candidacy) {
+        let mut candidates = self.candidates.borrow_mut();
+        candidates.retain(|_, (_, seen)| seen.elapsed() < CANDIDATE_TIMEOUT);
+        let leader = candidates
+            .iter()
+            .map(|(id, (candidacy, _))| (*candidacy, id.as_slice()))
+            .chain(std::iter::once((own, id)))
+            .max()
+            .map(|(_, id)| id.to_base58())
+            .unwrap_or_default();
+        if self.leader().as_deref() == Some(leader.as_str()) {
+            return;
+        }
+        let role = match leader == id.to_base58() {
+            true => ROLE_COORDINATOR,
+            false => ROLE_MEMBER,
+        };
+        println!("{leader} is coordinator of {} nodes", candidates.len() + 1);
+        self.leader.set(Value::String(leader));
+        if self.role.get().as_str() != Some(role) {
+            self.role.set(Value::String(String::from(role)));
+        }
+    }
+
+    // Sequence numbers only grow across reboots, so anything not past the
+    // last one seen from `id` is a replay. The marks are not stored, so
+    // after this node reboots a recorded candidacy passes again until a
+    // live one from the same node overtakes it.
+    fn is_fresh(&self, id: &[u8], seq: u32) -> bool {
+        let mut last_seq = self.last_seq.borrow_mut();
+        if let Some(last) = last_seq.get(id) {
+            if seq.wrapping_sub(*last) as i32 <= 0 {
+                return false;
+            }
+        } else if last_seq.len() >= MAX_SEEN {
+            last_seq.pop_first();
+        }
+        last_seq.insert(id.to_vec(), seq);
+        true
+    }
+
+    pub async fn run_handle(&self, espnow: &EspNowService, wifi: &WifiService<'_>) -> Result<()> {
+        let beacon = async {
+            let mut failing = false;
+            loop {
+                let own = self.candidacy(wifi);
+                match espnow.send_candidacy(own) {
+                    Ok(()) => failing = false,
+                    Err(e) if !failing => {
+                        println!("cannot send candidacy: {e}");
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+                self.elect(espnow.id(), own);
+                futures_timer::Delay::new(BEACON_INTERVAL).await;
+            }
+        };
+        let listen = async {
+            loop {
+                let (id, seq, candidacy) = espnow.next_candidacy().await?;
+                if !self.is_fresh(&id, seq) {
+                    continue;
+                }
+                self.candidates
+                    .borrow_mut()
+                    .insert(id, (candidacy, Instant::now()));
+                self.elect(espnow.id(), self.candidacy(wifi));
+            }
+        };
+        or(beacon, listen).await
+    }
+}
+
+impl Schema for ElectionService {
+    fn get_schema(&self) -> DataSchema {
+        let priority = DataSchema {
+            id: self.priority.get_key().to_string(),
+            title: Some(String::from("Coordinator priority")),
+            description: Some(String::from(
+                "Breaks ties between nodes with the same connectivity and power",
+            )),
+            local_only: true,
+            detail: DetailDataSchema::Integer {
+                minimum: Some(0),
+                maximum: Some(255),
+            },
+            ..Default::default()
+        };
+        let mains = DataSchema {
+            id: self.mains.get_key().to_string(),
+            title: Some(String::from("Mains powered")),
+            local_only: true,
+            detail: DetailDataSchema::Bool,
+            ..Default::default()
+        };
+        let leader = DataSchema {
+            id: self.leader.get_key().to_string(),
+            title: Some(String::from("Coordinator")),
+            read_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let role = DataSchema {
+            id: self.role.get_key().to_string(),
+            title: Some(String::from("Role")),
+            read_only: true,
+            description: Some(String::from("coordinator or member")),
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let mut map = BTreeMap::new();
+        map.insert(priority.id.clone(), priority);
+        map.insert(mains.id.clone(), mains);
+        map.insert(leader.id.clone(), leader);
+        map.insert(role.id.clone(), role);
+        DataSchema {
+            id: String::from("election"),
+            title: Some(String::from("Coordinator election")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
+    }
+}
@@ -1,126 +1,1094 @@
-use anyhow::Result;
+mod link;
+mod mesh;
+mod neighbours;
+mod peers;
+pub mod protocol;
+mod radio;
+#[cfg(feature = "sim")]
+pub mod sim;
+
+use anyhow::{anyhow, bail, Result};
 use async_channel::{bounded, Receiver, Sender};
-use async_mutex::Mutex;
+use base58::ToBase58;
+use blake2::{digest::Mac, Blake2sMac256};
 use dashmap::DashMap;
-use esp_idf_svc::espnow::{EspNow, BROADCAST};
-use esp_idf_sys::esp_wifi_get_mac;
-use serde::{de::DeserializeOwned, Serialize};
-use std::{borrow::Borrow, rc::Rc};
+use event_listener::Event;
+use futures_lite::future::or;
+use mesh::{RouteTable, MAX_HOPS};
+use neighbours::{Neighbours, NONCE_LEN};
+use peers::{PeerStatus, PeerTable, MAX_ENCRYPTED_PEERS, MAX_PEERS};
+use protocol::{Kind, Message, MessageHandler};
+use radio::{PeerConfig, BROADCAST, MAX_DATA_LEN};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, VecDeque},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+pub use mesh::Route;
+#[cfg(target_os = "espidf")]
+pub use radio::EspNowRadio;
+pub use radio::{Interface, Radio, Uplink};
+
+use crate::{
+    connection::Connection,
+    data_schema::{DataSchema, DetailDataSchema, Schema},
+    identity::Identity,
+    pairing::PairingService,
+    storage::{StorageEntry, StorageService},
+    topics::{TopicService, TAG_LEN},
+    utils::now_micros,
+};
+
+// Leaves room for the `Routed` and `Fragment` headers inside one frame.
+const FRAGMENT_LEN: usize = MAX_DATA_LEN - 48;
+const MAX_MESSAGE_LEN: usize = 16 * 1024;
+const MAX_PARTIALS: usize = 4;
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(3);
+const SEND_RETRIES: u32 = 5;
+const ACK_TIMEOUT: Duration = Duration::from_millis(200);
+const RETRANSMITS: u32 = 4;
+const DEDUP_WINDOW: usize = 32;
+// Deep enough to hold a burst of fragments while the executor catches up.
+const FRAME_QUEUE: usize = 32;
+const LINK_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_CHANNEL: u8 = 13;
+const SEQ_BLOCK: u32 = 4096;
+// Long enough for a queued advert to leave before the radio moves on.
+const ANNOUNCE_TIME: Duration = Duration::from_millis(50);
+
+// What goes over the air, hop by hop.
+#[derive(Serialize, Deserialize)]
+enum Frame<'a> {
+    Advertise {
+        id: &'a [u8],
+        channel: u8,
+        // Set when the sender's channel is fixed by its STA connection.
+        anchored: bool,
+        routes: Vec<(&'a [u8], u8)>,
+    },
+    Routed {
+        src: &'a [u8],
+        dst: &'a [u8],
+        ttl: u8,
+        seq: u32,
+        payload: &'a [u8],
+    },
+    // Flooded to the whole mesh; each node applies it if subscribed.
+    Publish {
+        src: &'a [u8],
+        topic: &'a str,
+        ttl: u8,
+        seq: u32,
+        payload: &'a [u8],
+        tag: Option<[u8; TAG_LEN]>,
+    },
+    // One hop only: every hop adds asymmetric delay, so strata grow per
+    // hop instead.
+    Time(TimeMessage),
+    // Flooded like `Publish` so the whole mesh elects the same coordinator.
+    Candidate {
+        src: &'a [u8],
+        ttl: u8,
+        seq: u32,
+        candidacy: Candidacy,
+        tag: [u8; TAG_LEN],
+    },
+    // Broadcast, as the prover may not have us registered yet.
+    Challenge {
+        verifier: &'a [u8],
+        prover: &'a [u8],
+        nonce: [u8; NONCE_LEN],
+    },
+    Proof {
+        prover: &'a [u8],
+        verifier: &'a [u8],
+        nonce: [u8; NONCE_LEN],
+        tag: [u8; TAG_LEN],
+    },
+}
+
+// Fields in order of precedence, so the derived ordering ranks candidates.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Candidacy {
+    pub sta_connected: bool,
+    pub mains_powered: bool,
+    pub priority: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TimeMessage {
+    Request {
+        client: Vec<u8>,
+        t1: u64,
+    },
+    Response {
+        server: Vec<u8>,
+        client: Vec<u8>,
+        stratum: u8,
+        t1: u64,
+        t2: u64,
+        t3: u64,
+        // Under the key the server shares with the client.
+        tag: [u8; TAG_LEN],
+    },
+}
+
+// What travels end to end inside `Frame::Routed`.
+#[derive(Serialize, Deserialize)]
+enum Payload<'a> {
+    Fragment {
+        msg_id: u16,
+        index: u8,
+        total: u8,
+        reliable: bool,
+        data: &'a [u8],
+    },
+    Ack {
+        msg_id: u16,
+    },
+}
+
+struct Partial {
+    msg_id: u16,
+    started: Instant,
+    parts: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+// Fragments of one message may interleave with those of the next after a
+// loss, so a few are assembled side by side and stale ones are dropped.
+#[derive(Default)]
+struct Reassembler {
+    partials: Vec<Partial>,
+}
+
+impl Reassembler {
+    fn push(&mut self, msg_id: u16, index: u8, total: u8, data: &[u8]) -> Option<Vec<u8>> {
+        let (index, total) = (index as usize, total as usize);
+        if index >= total {
+            return None;
+        }
+        if total == 1 {
+            return Some(data.to_vec());
+        }
+        self.partials
+            .retain(|p| p.started.elapsed() < REASSEMBLY_TIMEOUT);
+        let pos = match self.partials.iter().position(|p| p.msg_id == msg_id) {
+            Some(pos) if self.partials[pos].parts.len() == total => pos,
+            Some(pos) => {
+                self.partials.remove(pos);
+                return None;
+            }
+            None => {
+                if self.partials.len() >= MAX_PARTIALS {
+                    self.partials.remove(0);
+                }
+                self.partials.push(Partial {
+                    msg_id,
+                    started: Instant::now(),
+                    parts: vec![None; total],
+                    missing: total,
+                });
+                self.partials.len() - 1
+            }
+        };
+        let partial = &mut self.partials[pos];
+        if partial.parts[index].is_none() {
+            partial.parts[index] = Some(data.to_vec());
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return None;
+        }
+        let partial = self.partials.remove(pos);
+        Some(partial.parts.into_iter().flatten().flatten().collect())
+    }
+}
 
-use crate::{controller::Connection, wifi::WifiService};
+// The peer received the message and said no, as opposed to it never
+// arriving.
+#[derive(Debug)]
+pub struct Refused(pub String);
 
-pub fn get_mac() -> [u8; 6] {
-    let mut mac = [0u8; 6];
-    unsafe {
-        esp_wifi_get_mac(0, &mut mac as *mut u8);
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
-    mac
 }
 
-type Incoming = Receiver<([u8; 6], Receiver<Vec<u8>>)>;
-type IncomingTx = Sender<([u8; 6], Receiver<Vec<u8>>)>;
+impl std::error::Error for Refused {}
+
+pub struct Publication {
+    pub src: Vec<u8>,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+type PendingAcks = DashMap<(Vec<u8>, u16), Sender<()>>;
+type PendingRequests = DashMap<(String, u32), Sender<Message>>;
+type Incoming = Receiver<(Vec<u8>, Receiver<Vec<u8>>)>;
+type IncomingTx = Sender<(Vec<u8>, Receiver<Vec<u8>>)>;
 #[derive(Clone)]
 pub struct EspNowService {
-    espnow: Rc<EspNow>,
+    radio: Rc<dyn Radio>,
     incoming: Incoming,
     incoming_tx: IncomingTx,
-    handlers: DashMap<[u8; 6], Sender<Vec<u8>>>,
+    handlers: Rc<DashMap<Vec<u8>, Sender<Vec<u8>>>>,
+    heard: Rc<DashMap<Vec<u8>, Rc<Event>>>,
+    routes: Rc<RefCell<RouteTable>>,
+    neighbours: Rc<RefCell<Neighbours>>,
+    seq: Rc<Cell<u32>>,
+    seq_reserved: StorageEntry,
+    acks: Rc<PendingAcks>,
+    requests: Rc<PendingRequests>,
+    request_id: Rc<Cell<u32>>,
+    id: Vec<u8>,
+    identity: Identity,
+    pairing: PairingService,
+    topics: TopicService,
+    publications: Receiver<Publication>,
+    publications_tx: Sender<Publication>,
+    time: Receiver<(TimeMessage, u64)>,
+    time_tx: Sender<(TimeMessage, u64)>,
+    candidacies: Receiver<(Vec<u8>, u32, Candidacy)>,
+    candidacies_tx: Sender<(Vec<u8>, u32, Candidacy)>,
+    channel: Rc<Cell<u8>>,
+    interface: Rc<Cell<Interface>>,
+    anchored: Rc<Cell<bool>>,
+    peers: Rc<RefCell<PeerTable>>,
+    peer_cap: StorageEntry,
+    peer_count: StorageEntry,
+    peers_added: StorageEntry,
+    peers_evicted: StorageEntry,
+    peers_rejected: StorageEntry,
+    encrypted_peers: StorageEntry,
+    network_key: StorageEntry,
+    storage: StorageService,
     raw_rx: Receiver<([u8; 6], Vec<u8>)>,
 }
 
 impl EspNowService {
-    pub fn new(wifi: &WifiService) -> anyhow::Result<Self> {
-        let interface = wifi.active_interface();
-        let espnow = EspNow::take()?;
-        espnow.add_peer(esp_idf_sys::esp_now_peer_info {
-            peer_addr: BROADCAST,
-            ifidx: interface,
-            ..Default::default()
-        })?;
+    pub fn new(
+        radio: Rc<dyn Radio>,
+        wifi: &dyn Uplink,
+        storage: &StorageService,
+        identity: &Identity,
+        pairing: &PairingService,
+        topics: &TopicService,
+    ) -> anyhow::Result<Self> {
+        if let Some(pmk) = identity.pmk()? {
+            radio.set_pmk(&pmk)?;
+        }
         let (incoming_tx, incoming) = bounded(10);
-        let (raw_tx, raw_rx) = bounded(10);
-
-        espnow.register_recv_cb(move |addr, data| {
-            let addr: [u8; 6] = addr.try_into().unwrap();
-            raw_tx.try_send((addr, data.to_vec())).ok();
-        })?;
-        Ok(Self {
-            espnow: Rc::new(espnow),
+        let (publications_tx, publications) = bounded(FRAME_QUEUE);
+        let (time_tx, time) = bounded(FRAME_QUEUE);
+        let (candidacies_tx, candidacies) = bounded(FRAME_QUEUE);
+        let seq_reserved = storage.entry("espnow_seq");
+        let seq = match seq_reserved.get().as_u64() {
+            Some(seq) => seq as u32,
+            None => radio.random(),
+        };
+        let this = Self {
+            raw_rx: radio.frames(),
+            radio,
             incoming,
             incoming_tx,
-            raw_rx,
-            handlers: DashMap::new(),
-        })
+            handlers: Rc::new(DashMap::new()),
+            heard: Rc::new(DashMap::new()),
+            routes: Rc::new(RefCell::new(RouteTable::default())),
+            neighbours: Rc::new(RefCell::new(Neighbours::default())),
+            seq: Rc::new(Cell::new(seq)),
+            seq_reserved,
+            acks: Rc::new(DashMap::new()),
+            requests: Rc::new(DashMap::new()),
+            request_id: Rc::new(Cell::new(0)),
+            id: identity.id(),
+            identity: identity.clone(),
+            pairing: pairing.clone(),
+            topics: topics.clone(),
+            publications,
+            publications_tx,
+            time,
+            time_tx,
+            candidacies,
+            candidacies_tx,
+            channel: Rc::new(Cell::new(wifi.channel()?)),
+            interface: Rc::new(Cell::new(wifi.interface())),
+            anchored: Rc::new(Cell::new(false)),
+            peers: Rc::new(RefCell::new(PeerTable::default())),
+            peer_cap: storage.entry("espnow_peer_cap"),
+            peer_count: storage.entry("espnow_peer_count"),
+            peers_added: storage.entry("espnow_peers_added"),
+            peers_evicted: storage.entry("espnow_peers_evicted"),
+            peers_rejected: storage.entry("espnow_peers_rejected"),
+            encrypted_peers: storage.entry("espnow_encrypted_peers"),
+            network_key: storage.entry("espnow_network_key"),
+            storage: storage.clone(),
+        };
+        this.peer_cap.get_or_init(|| Value::from(MAX_PEERS));
+        this.network_key.set_unnotice(Value::from(""));
+        this.reserve_seq(seq)?;
+        this.ensure_peer(BROADCAST)?;
+        Ok(this)
+    }
+    pub async fn run_handle(&self) -> Result<()> {
+        let report = async {
+            loop {
+                self.report_links();
+                futures_timer::Delay::new(LINK_REPORT_INTERVAL).await;
+            }
+        };
+        or(self.handle_frames(), or(self.handle_network_key(), report)).await
     }
-    pub async fn reactor_tick(&self) {
-        static RUN_LOCK: Mutex<()> = Mutex::new(());
-        if RUN_LOCK.try_lock().is_some() {
-            if let Ok((addr, data)) = self.raw_rx.recv().await {
-                if !self.espnow.peer_exists(addr).unwrap() {
-                    self.espnow
-                        .add_peer(esp_idf_sys::esp_now_peer_info {
-                            peer_addr: addr,
-                            channel: 0,
-                            ifidx: 1,
-                            ..Default::default()
-                        })
-                        .unwrap();
-                    let (tx, rx) = bounded(10);
-                    self.handlers.insert(addr, tx.clone());
-                    self.incoming_tx.send((addr, rx)).await.unwrap();
+    async fn handle_frames(&self) -> Result<()> {
+        while let Ok((addr, data)) = self.raw_rx.recv().await {
+            self.peers.as_ref().borrow_mut().touch(addr);
+            link::record_received(addr);
+            match postcard::from_bytes(&data) {
+                Ok(Frame::Advertise {
+                    id,
+                    channel,
+                    anchored,
+                    routes,
+                }) => {
+                    // Routes are only taken from paired neighbours that have
+                    // proven their id, or anyone could pull traffic and the
+                    // link key towards themselves.
+                    if !self.pairing.is_paired(&id.to_base58()) {
+                        continue;
+                    }
+                    if self.neighbour(&addr).as_deref() != Some(id) {
+                        self.challenge(addr, id);
+                        continue;
+                    }
+                    self.routes.borrow_mut().learn(&self.id, addr, id, &routes);
+                    self.heard(id);
+                    // A paired neighbour must be registered with its link key
+                    // before its encrypted frames can be read here; it is
+                    // also dialled, so queued writes reach it as it wakes.
+                    self.ensure_peer(addr).ok();
+                    self.open_channel(addr, id);
+                    // Only a node without its own router follows, so two
+                    // free nodes never chase each other between channels.
+                    if anchored && !self.anchored.get() && channel != self.channel.get() {
+                        if let Err(e) = self.follow(id, channel) {
+                            println!("cannot follow {}: {e}", id.to_base58());
+                        }
+                    }
+                }
+                Ok(Frame::Routed {
+                    src,
+                    dst,
+                    ttl,
+                    seq,
+                    payload,
+                }) => {
+                    if src == self.id.as_slice() || self.routes.borrow_mut().is_duplicate(src, seq)
+                    {
+                        continue;
+                    }
+                    // Anyone may reach us directly, e.g. to pair, but only
+                    // proven neighbours are routes or get frames relayed.
+                    let trusted = self.neighbour(&addr).is_some();
+                    if trusted {
+                        self.routes
+                            .borrow_mut()
+                            .offer(src, addr, MAX_HOPS.saturating_sub(ttl) + 1);
+                    }
+                    if dst == self.id.as_slice() {
+                        self.heard(src);
+                        self.deliver(addr, src, payload);
+                    } else if ttl > 1 && trusted {
+                        let frame = Frame::Routed {
+                            src,
+                            dst,
+                            ttl: ttl - 1,
+                            seq,
+                            payload,
+                        };
+                        self.forward(dst, &postcard::to_allocvec(&frame)?).ok();
+                    }
+                }
+                Ok(Frame::Publish {
+                    src,
+                    topic,
+                    ttl,
+                    seq,
+                    payload,
+                    tag,
+                }) => {
+                    if self.neighbour(&addr).is_none()
+                        || src == self.id.as_slice()
+                        || self.routes.borrow_mut().is_duplicate(src, seq)
+                    {
+                        continue;
+                    }
+                    let paired = self.pairing.is_paired(&src.to_base58());
+                    if self
+                        .topics
+                        .accepts(src, topic, seq, payload, tag.as_ref(), paired)
+                    {
+                        let publication = Publication {
+                            src: src.to_vec(),
+                            topic: topic.to_string(),
+                            payload: payload.to_vec(),
+                        };
+                        self.publications_tx.try_send(publication).ok();
+                    }
+                    // Members may be out of the publisher's reach, so every
+                    // node repeats the frame whether subscribed or not.
+                    if ttl > 1 {
+                        let frame = Frame::Publish {
+                            src,
+                            topic,
+                            ttl: ttl - 1,
+                            seq,
+                            payload,
+                            tag,
+                        };
+                        self.send(BROADCAST, &postcard::to_allocvec(&frame)?).ok();
+                    }
+                }
+                Ok(Frame::Candidate {
+                    src,
+                    ttl,
+                    seq,
+                    candidacy,
+                    tag,
+                }) => {
+                    // Checked before the duplicate filter so forged frames
+                    // cannot use up a real candidate's sequence numbers.
+                    let authentic = match self.candidacy_mac(src, seq, &candidacy) {
+                        Ok(Some(mac)) => mac.verify_truncated_left(&tag).is_ok(),
+                        _ => false,
+                    };
+                    if !authentic
+                        || src == self.id.as_slice()
+                        || self.routes.borrow_mut().is_duplicate(src, seq)
+                    {
+                        continue;
+                    }
+                    self.candidacies_tx
+                        .try_send((src.to_vec(), seq, candidacy))
+                        .ok();
+                    if ttl > 1 {
+                        let frame = Frame::Candidate {
+                            src,
+                            ttl: ttl - 1,
+                            seq,
+                            candidacy,
+                            tag,
+                        };
+                        self.send(BROADCAST, &postcard::to_allocvec(&frame)?).ok();
+                    }
+                }
+                Ok(Frame::Time(message)) => {
+                    self.time_tx.try_send((message, now_micros())).ok();
                 }
-                self.handlers.retain(|_k, s| !s.is_closed());
-                if let Some(sender) = self.handlers.get(&addr) {
-                    sender.send(data).await.ok();
+                Ok(Frame::Challenge {
+                    verifier,
+                    prover,
+                    nonce,
+                }) => {
+                    if prover == self.id.as_slice() {
+                        if let Err(e) = self.prove(verifier, nonce) {
+                            println!("cannot answer {}: {e}", verifier.to_base58());
+                        }
+                    }
                 }
+                Ok(Frame::Proof {
+                    prover,
+                    verifier,
+                    nonce,
+                    tag,
+                }) => {
+                    if verifier != self.id.as_slice()
+                        || !self.neighbours.borrow().is_pending(&addr, prover, &nonce)
+                    {
+                        continue;
+                    }
+                    let valid = self
+                        .proof(prover, prover, verifier, &nonce)
+                        .is_some_and(|proof| proof.verify_truncated_left(&tag).is_ok());
+                    if valid {
+                        println!("{} is at {}", prover.to_base58(), addr.to_base58());
+                        self.neighbours.borrow_mut().verify(addr, prover);
+                        self.ensure_peer(addr).ok();
+                        self.open_channel(addr, prover);
+                    }
+                }
+                Err(_) => (),
             }
         }
+        bail!("espnow receive channel closed")
     }
-    pub async fn run_handle(&self) {
-        while let Ok((addr, data)) = self.raw_rx.recv().await {
-            if !self.espnow.peer_exists(addr).unwrap() {
-                self.espnow
-                    .add_peer(esp_idf_sys::esp_now_peer_info {
-                        peer_addr: addr,
-                        channel: 0,
-                        ifidx: 1,
-                        ..Default::default()
-                    })
-                    .unwrap();
-                let (tx, rx) = bounded(10);
-                self.handlers.insert(addr, tx.clone());
-                self.incoming_tx.send((addr, rx)).await.unwrap();
+    fn deliver(&self, addr: [u8; 6], src: &[u8], payload: &[u8]) {
+        // Only peers claiming a paired id get a channel, unless the pairing
+        // window is open; the handshake then checks the claim.
+        if !self.pairing.admits(src) {
+            return;
+        }
+        // Acks are settled here so they arrive even while nobody is
+        // reading from the peer's channel.
+        if let Ok(Payload::Ack { msg_id }) = postcard::from_bytes(payload) {
+            if let Some((_, tx)) = self.acks.remove(&(src.to_vec(), msg_id)) {
+                tx.try_send(()).ok();
             }
-            self.handlers.retain(|_k, s| !s.is_closed());
-            if let Some(sender) = self.handlers.get(&addr) {
-                sender.send(data).await.ok();
+            return;
+        }
+        if !self.open_channel(addr, src) {
+            return;
+        }
+        // A peer that is not reading must not stall every other one.
+        if let Some(sender) = self.handlers.get(src) {
+            if sender.try_send(payload.to_vec()).is_err() {
+                link::record_dropped(addr);
             }
         }
     }
-    pub fn send(&self, addr: [u8; 6], data: &[u8]) -> Result<()> {
-        self.espnow.as_ref().borrow().send(addr, data)?;
+    fn heard(&self, id: &[u8]) {
+        if let Some(event) = self.heard.get(id) {
+            event.notify(usize::MAX);
+        }
+    }
+    // Wakes when `id` is heard from, whether it advertises next door or
+    // sends anything across the mesh.
+    pub async fn wait_heard(&self, id: &[u8]) {
+        let event = self.heard.entry(id.to_vec()).or_default().clone();
+        event.listen().await;
+    }
+    // A dropped channel (e.g. a failed handshake) is reopened on the next
+    // frame from that peer.
+    fn open_channel(&self, addr: [u8; 6], id: &[u8]) -> bool {
+        self.handlers.retain(|_k, s| !s.is_closed());
+        if self.handlers.contains_key(id) {
+            return true;
+        }
+        let (tx, rx) = bounded(FRAME_QUEUE);
+        // With the controller behind, the peer retries on its next frame.
+        if self.incoming_tx.try_send((id.to_vec(), rx)).is_err() {
+            link::record_dropped(addr);
+            return false;
+        }
+        self.handlers.insert(id.to_vec(), tx);
+        true
+    }
+    // Unknown destinations are flooded; TTL and the duplicate filter keep
+    // the flood from circulating.
+    fn forward(&self, dst: &[u8], frame: &[u8]) -> Result<()> {
+        let next_hop = self.routes.borrow_mut().next_hop(dst);
+        match next_hop {
+            Some(via) => {
+                self.ensure_peer(via)?;
+                self.send(via, frame)
+            }
+            None => self.send(BROADCAST, frame),
+        }
+    }
+    // Our own sequence numbers are marked as seen so echoes are dropped.
+    fn next_seq(&self) -> u32 {
+        let seq = self.seq.get();
+        if self.seq_reserved.get().as_u64() == Some(seq as u64) {
+            if let Err(e) = self.reserve_seq(seq) {
+                println!("cannot reserve sequence numbers: {e}");
+            }
+        }
+        self.seq.set(seq.wrapping_add(1));
+        self.routes.borrow_mut().is_duplicate(&self.id, seq);
+        seq
+    }
+    // Topic and election receivers drop anything not newer than what they
+    // last saw from us, so the numbers keep rising across our reboots: a
+    // block is stored before it is used. Their marks live in memory, and
+    // the mesh's own duplicate filter only spans recent frames.
+    fn reserve_seq(&self, from: u32) -> Result<()> {
+        self.seq_reserved
+            .set(Value::from(from.wrapping_add(SEQ_BLOCK)));
+        self.storage.store()
+    }
+    fn send_routed(&self, dst: &[u8], payload: &[u8]) -> Result<()> {
+        let seq = self.next_seq();
+        let frame = Frame::Routed {
+            src: &self.id,
+            dst,
+            ttl: MAX_HOPS,
+            seq,
+            payload,
+        };
+        self.forward(dst, &postcard::to_allocvec(&frame)?)
+    }
+    // The key is written once and never read back; the property only ever
+    // holds an empty string.
+    async fn handle_network_key(&self) -> Result<()> {
+        loop {
+            let key = self.network_key.wait_new().await;
+            match key.as_str() {
+                Some(key) if !key.is_empty() => {
+                    self.network_key.set_unnotice(Value::from(""));
+                    if let Err(e) = self.set_network_key(key.as_bytes()) {
+                        println!("cannot set espnow network key: {e}");
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    fn set_network_key(&self, key: &[u8]) -> Result<()> {
+        self.identity.set_network_key(key)?;
+        if let Some(pmk) = self.identity.pmk()? {
+            self.radio.set_pmk(&pmk)?;
+        }
+        // Link keys are encrypted with the PMK, so they are installed again.
+        self.register_all()
+    }
+    // The paired thing that has proven it answers at this address.
+    fn neighbour(&self, addr: &[u8; 6]) -> Option<Vec<u8>> {
+        let id = self.neighbours.borrow_mut().id(addr)?;
+        self.pairing.is_paired(&id.to_base58()).then_some(id)
+    }
+    fn challenge(&self, addr: [u8; 6], id: &[u8]) {
+        let nonce = self.nonce();
+        if !self.neighbours.borrow_mut().challenge(addr, id, nonce) {
+            return;
+        }
+        let frame = Frame::Challenge {
+            verifier: &self.id,
+            prover: id,
+            nonce,
+        };
+        let sent = postcard::to_allocvec(&frame)
+            .map_err(anyhow::Error::from)
+            .and_then(|frame| self.send(BROADCAST, &frame));
+        if let Err(e) = sent {
+            println!("cannot challenge {}: {e}", id.to_base58());
+        }
+    }
+    fn prove(&self, verifier: &[u8], nonce: [u8; NONCE_LEN]) -> Result<()> {
+        let proof = self
+            .proof(verifier, &self.id, verifier, &nonce)
+            .ok_or_else(|| anyhow!("not paired"))?;
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&proof.finalize().into_bytes()[..TAG_LEN]);
+        let frame = Frame::Proof {
+            prover: &self.id,
+            verifier,
+            nonce,
+            tag,
+        };
+        self.send(BROADCAST, &postcard::to_allocvec(&frame)?)
+    }
+    // Keyed by what only this thing and the paired `peer` can derive.
+    fn proof(
+        &self,
+        peer: &[u8],
+        prover: &[u8],
+        verifier: &[u8],
+        nonce: &[u8; NONCE_LEN],
+    ) -> Option<Blake2sMac256> {
+        let public_key = self.pairing.public_key(&peer.to_base58())?;
+        let key = self.identity.auth_key(&public_key).ok()?;
+        Some(neighbours::proof(&key, prover, verifier, nonce))
+    }
+    // Keyed by what only this thing and the paired `peer` can derive; the
+    // label keeps tags made for one purpose from passing for another.
+    pub fn peer_mac(&self, peer: &[u8], label: &str) -> Option<Blake2sMac256> {
+        let public_key = self.pairing.public_key(&peer.to_base58())?;
+        let key = self.identity.auth_key(&public_key).ok()?;
+        let mac = <Blake2sMac256 as Mac>::new_from_slice(&key).ok()?;
+        Some(mac.chain_update(label.as_bytes()))
+    }
+    fn nonce(&self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        for chunk in nonce.chunks_mut(4) {
+            chunk.copy_from_slice(&self.radio.random().to_le_bytes());
+        }
+        nonce
+    }
+    // Only proven neighbours get a link key; everyone else, and the
+    // broadcast entry, stays in the clear.
+    fn link_key(&self, addr: &[u8; 6]) -> Option<[u8; 16]> {
+        if *addr == BROADCAST {
+            return None;
+        }
+        let neighbour = self.neighbour(addr)?;
+        let public_key = self.pairing.public_key(&neighbour.to_base58())?;
+        self.identity.link_key(&public_key).ok()
+    }
+    fn peer_config(&self, addr: [u8; 6], lmk: Option<[u8; 16]>) -> PeerConfig {
+        PeerConfig {
+            addr,
+            channel: self.channel.get(),
+            interface: self.interface.get(),
+            lmk,
+        }
+    }
+    fn peer_cap(&self) -> usize {
+        let cap = self.peer_cap.get().as_u64().unwrap_or(MAX_PEERS as u64);
+        cap.clamp(2, MAX_PEERS as u64) as usize
+    }
+    // The broadcast entry and the hops towards paired things are never
+    // evicted; everything else is fair game, least recently used first.
+    fn is_pinned(&self, addr: &[u8; 6]) -> bool {
+        *addr == BROADCAST
+            || self.routes().iter().any(|(dest, route)| {
+                route.via == *addr && self.pairing.is_paired(&dest.to_base58())
+            })
+    }
+    fn ensure_peer(&self, addr: [u8; 6]) -> Result<()> {
+        let mut lmk = self.link_key(&addr);
+        let mut peers = self.peers.as_ref().borrow_mut();
+        if lmk.is_some()
+            && !peers.is_encrypted(&addr)
+            && peers.encrypted_count() >= MAX_ENCRYPTED_PEERS as usize
+        {
+            println!("no encrypted peer slot left for {}", addr.to_base58());
+            lmk = None;
+        }
+        if peers.contains(&addr) {
+            peers.touch(addr);
+            if peers.is_encrypted(&addr) != lmk.is_some() {
+                self.radio.set_peer(&self.peer_config(addr, lmk))?;
+                peers.set_encrypted(&addr, lmk.is_some());
+                drop(peers);
+                self.update_peer_metrics();
+            }
+            return Ok(());
+        }
+        while peers.len() >= self.peer_cap() {
+            match peers.victim(|addr| self.is_pinned(addr)) {
+                Some(victim) => {
+                    self.radio.remove_peer(victim).ok();
+                    peers.remove(&victim);
+                    link::forget(&victim);
+                    peers.metrics.evicted += 1;
+                }
+                None => {
+                    peers.metrics.rejected += 1;
+                    drop(peers);
+                    self.update_peer_metrics();
+                    bail!("espnow peer table is full");
+                }
+            }
+        }
+        self.radio.set_peer(&self.peer_config(addr, lmk))?;
+        peers.insert(addr, lmk.is_some());
+        link::track(addr);
+        peers.metrics.added += 1;
+        drop(peers);
+        self.update_peer_metrics();
+        Ok(())
+    }
+    pub fn remove_peer(&self, addr: [u8; 6]) -> Result<()> {
+        if addr == BROADCAST {
+            bail!("the broadcast peer cannot be removed");
+        }
+        if !self.peers.as_ref().borrow_mut().remove(&addr) {
+            bail!("{} is not a registered peer", addr.to_base58());
+        }
+        self.radio.remove_peer(addr)?;
+        link::forget(&addr);
+        self.update_peer_metrics();
+        Ok(())
+    }
+    fn update_peer_metrics(&self) {
+        let peers = self.peers.as_ref().borrow();
+        self.peer_count.set(Value::from(peers.len()));
+        self.peers_added.set(Value::from(peers.metrics.added));
+        self.peers_evicted.set(Value::from(peers.metrics.evicted));
+        self.peers_rejected.set(Value::from(peers.metrics.rejected));
+        self.encrypted_peers
+            .set(Value::from(peers.encrypted_count()));
+    }
+    // End-to-end delivery time is booked against the first hop.
+    fn record_latency(&self, dst: &[u8], latency: Duration) {
+        if let Some(via) = self.routes.borrow_mut().next_hop(dst) {
+            link::record_latency(via, latency);
+        }
+    }
+    fn link_property(addr: &[u8; 6]) -> String {
+        format!("espnow_link_{}", addr.to_base58())
+    }
+    // Only registered peers get a property; the rest are dropped so the
+    // stored map does not grow with every address ever heard.
+    fn report_links(&self) {
+        let registered = self.peers.as_ref().borrow().addrs();
+        for key in self.storage.keys() {
+            let stale = key.starts_with("espnow_link_")
+                && !registered
+                    .iter()
+                    .any(|addr| Self::link_property(addr) == key);
+            if stale {
+                self.storage.remove(&key);
+            }
+        }
+        for addr in registered {
+            let key = Self::link_property(&addr);
+            let summary = Value::String(link::get(&addr).summary());
+            if self.storage.get(&key) != summary {
+                self.storage.set(&key, summary);
+            }
+        }
+    }
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        self.peers.as_ref().borrow().status()
+    }
+    // Peers keep the channel and interface they were added with, so every
+    // one of them is re-registered when the radio moves.
+    fn set_channel(&self, channel: u8, interface: Interface) -> Result<()> {
+        self.channel.set(channel);
+        self.interface.set(interface);
+        self.register_all()
+    }
+    fn register_all(&self) -> Result<()> {
+        let registered = self.peers.as_ref().borrow().addrs();
+        for addr in registered {
+            let encrypted = self.peers.as_ref().borrow().is_encrypted(&addr);
+            let lmk = self.link_key(&addr).filter(|_| encrypted);
+            self.radio.set_peer(&self.peer_config(addr, lmk))?;
+            self.peers
+                .as_ref()
+                .borrow_mut()
+                .set_encrypted(&addr, lmk.is_some());
+        }
+        self.update_peer_metrics();
         Ok(())
     }
+    pub fn channel(&self) -> u8 {
+        self.channel.get()
+    }
+    pub async fn track_channel(&self, wifi: &dyn Uplink) -> Result<()> {
+        loop {
+            let channel = wifi.channel()?;
+            let interface = wifi.interface();
+            self.anchored.set(interface == Interface::Station);
+            if channel != self.channel.get() || interface != self.interface.get() {
+                println!("espnow moves to channel {channel}, interface {interface:?}");
+                if channel != self.channel.get() {
+                    self.announce(channel).await;
+                }
+                self.set_channel(channel, interface)?;
+                self.advertise()?;
+            }
+            futures_timer::Delay::new(Duration::from_secs(1)).await;
+        }
+    }
+    // A bad channel in an advert must not take the radio, and the softAP's
+    // clients with it, somewhere it cannot go.
+    fn follow(&self, id: &[u8], channel: u8) -> Result<()> {
+        if !(1..=MAX_CHANNEL).contains(&channel) {
+            bail!("channel {channel} is out of range");
+        }
+        println!("following {} to channel {channel}", id.to_base58());
+        self.radio.set_channel(channel)?;
+        self.set_channel(channel, self.interface.get())
+    }
+    // The driver has already moved with the station, so the radio steps back
+    // long enough for the neighbours left behind to hear where it went.
+    async fn announce(&self, channel: u8) {
+        let announced = self
+            .radio
+            .set_channel(self.channel.get())
+            .and_then(|()| self.advertise_on(channel));
+        if let Err(e) = announced {
+            println!("cannot announce channel {channel}: {e}");
+        }
+        futures_timer::Delay::new(ANNOUNCE_TIME).await;
+        if let Err(e) = self.radio.set_channel(channel) {
+            println!("cannot return to channel {channel}: {e}");
+        }
+    }
+    pub fn send(&self, addr: [u8; 6], data: &[u8]) -> Result<()> {
+        self.radio.send(addr, data)
+    }
     pub async fn find_peer(&self) {}
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+    pub fn pairing(&self) -> &PairingService {
+        &self.pairing
+    }
+    pub fn topics(&self) -> &TopicService {
+        &self.topics
+    }
+    // Publications are single frames: no fragmentation and no acks.
+    pub fn publish(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        let seq = self.next_seq();
+        let frame = Frame::Publish {
+            src: &self.id,
+            topic,
+            ttl: MAX_HOPS,
+            seq,
+            payload,
+            tag: self.topics.tag(&self.id, topic, seq, payload),
+        };
+        let frame = postcard::to_allocvec(&frame)?;
+        if frame.len() > MAX_DATA_LEN {
+            bail!("publication on {topic} does not fit in one frame");
+        }
+        self.send(BROADCAST, &frame)
+    }
+    pub fn send_candidacy(&self, candidacy: Candidacy) -> Result<()> {
+        let seq = self.next_seq();
+        let Some(mac) = self.candidacy_mac(&self.id, seq, &candidacy)? else {
+            bail!("no network key to sign candidacies with");
+        };
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LEN]);
+        let frame = Frame::Candidate {
+            src: &self.id,
+            ttl: MAX_HOPS,
+            seq,
+            candidacy,
+            tag,
+        };
+        self.send(BROADCAST, &postcard::to_allocvec(&frame)?)
+    }
+    // Keyed by the network key rather than a pairing, so every node weighs
+    // the same candidates and elects the same coordinator.
+    fn candidacy_mac(
+        &self,
+        src: &[u8],
+        seq: u32,
+        candidacy: &Candidacy,
+    ) -> Result<Option<Blake2sMac256>> {
+        let Some(key) = self.identity.network_auth_key()? else {
+            return Ok(None);
+        };
+        let mac = <Blake2sMac256 as Mac>::new_from_slice(&key).expect("32 byte key");
+        Ok(Some(mac.chain_update(postcard::to_allocvec(&(
+            src, seq, candidacy,
+        ))?)))
+    }
+    pub async fn next_candidacy(&self) -> Result<(Vec<u8>, u32, Candidacy)> {
+        Ok(self.candidacies.recv().await?)
+    }
+    pub fn send_time(&self, message: TimeMessage) -> Result<()> {
+        self.send(BROADCAST, &postcard::to_allocvec(&Frame::Time(message))?)
+    }
+    // Paired with the local time the frame was taken off the air.
+    pub async fn next_time(&self) -> Result<(TimeMessage, u64)> {
+        Ok(self.time.recv().await?)
+    }
+    pub async fn next_publication(&self) -> Result<Publication> {
+        Ok(self.publications.recv().await?)
+    }
+    pub fn routes(&self) -> Vec<(Vec<u8>, Route)> {
+        self.routes.borrow_mut().routes()
+    }
     pub fn advertise(&self) -> Result<()> {
-        self.send(BROADCAST, &postcard::to_allocvec(&(None as Option<()>))?)
+        self.advertise_on(self.channel.get())
+    }
+    fn advertise_on(&self, channel: u8) -> Result<()> {
+        let routes = self.routes.borrow_mut().advertised();
+        let frame = Frame::Advertise {
+            id: &self.id,
+            channel,
+            anchored: self.anchored.get(),
+            routes: routes
+                .iter()
+                .map(|(dest, hops)| (dest.as_slice(), *hops))
+                .collect(),
+        };
+        self.send(BROADCAST, &postcard::to_allocvec(&frame)?)
+    }
+    // Reads application messages from an established connection until it
+    // fails: requests go to the handler and are answered, responses settle
+    // pending `request` calls and events are passed on.
+    pub async fn dispatch(
+        &self,
+        peer: &str,
+        connection: &dyn Connection,
+        handler: &dyn MessageHandler,
+    ) -> Result<()> {
+        loop {
+            let data = connection.recv().await?;
+            let envelope = match protocol::decode(&data) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    println!("invalid message from {peer}: {e}");
+                    continue;
+                }
+            };
+            match envelope.kind {
+                Kind::Request(id) => {
+                    let response = handler.handle_request(peer, envelope.message).await;
+                    let response = protocol::encode(Kind::Response(id), response)?;
+                    connection.send_reliable(&response).await.ok();
+                }
+                Kind::Response(id) => {
+                    if let Some((_, tx)) = self.requests.remove(&(peer.to_string(), id)) {
+                        tx.try_send(envelope.message).ok();
+                    }
+                }
+                Kind::Event => handler.handle_event(peer, envelope.message).await,
+            }
+        }
+    }
+    // Only answers arrive through `dispatch`, so it must be running for the
+    // same connection.
+    pub async fn request(
+        &self,
+        peer: &str,
+        connection: &dyn Connection,
+        message: Message,
+    ) -> Result<Message> {
+        let id = self.request_id.get().wrapping_add(1);
+        self.request_id.set(id);
+        let (tx, rx) = bounded(1);
+        let key = (peer.to_string(), id);
+        self.requests.insert(key.clone(), tx);
+        let result = async {
+            let request = protocol::encode(Kind::Request(id), message)?;
+            connection.send_reliable(&request).await?;
+            let response = async { Ok(rx.recv().await?) };
+            let timeout = async {
+                futures_timer::Delay::new(REQUEST_TIMEOUT).await;
+                Err(anyhow!("peer {peer} did not respond"))
+            };
+            or(response, timeout).await
+        }
+        .await;
+        self.requests.remove(&key);
+        match result? {
+            Message::Error(e) => Err(Refused(e).into()),
+            message => Ok(message),
+        }
+    }
+    pub async fn notify(&self, connection: &dyn Connection, message: Message) -> Result<()> {
+        connection
+            .send(&protocol::encode(Kind::Event, message)?)
+            .await
     }
-    pub async fn next_channel(&self) -> EspNowChannel {
-        let (addr, rx) = self.incoming.recv().await.unwrap();
+    pub async fn next_channel(&self) -> Result<EspNowChannel> {
+        let (id, rx) = self.incoming.recv().await?;
         println!("new channel");
-        EspNowChannel {
+        Ok(EspNowChannel {
             espnow: self.clone(),
-            addr,
+            id,
             rx,
-        }
+            // A fresh channel to a peer that still remembers recent ids
+            // from the previous one must not look like a duplicate.
+            next_msg_id: Rc::new(Cell::new(self.radio.random() as u16)),
+            reassembler: Rc::new(RefCell::new(Reassembler::default())),
+            delivered: Rc::new(RefCell::new(VecDeque::new())),
+        })
     }
 }
 
+// A conversation with one thing id, wherever it sits in the mesh.
 #[derive(Clone)]
 pub struct EspNowChannel {
     espnow: EspNowService,
-    addr: [u8; 6],
+    id: Vec<u8>,
     rx: Receiver<Vec<u8>>,
+    next_msg_id: Rc<Cell<u16>>,
+    reassembler: Rc<RefCell<Reassembler>>,
+    delivered: Rc<RefCell<VecDeque<u16>>>,
 }
 #[async_trait::async_trait(?Send)]
 impl Connection for EspNowChannel {
@@ -128,11 +1096,13 @@ impl Connection for EspNowChannel {
         self.is_initializer()
     }
     async fn remote_id(&self) -> Vec<u8> {
-        self.addr().to_vec()
+        self.id.clone()
     }
     async fn send(&self, data: &[u8]) -> anyhow::Result<()> {
-        self.send(data)?;
-        Ok(())
+        self.send(data).await
+    }
+    async fn send_reliable(&self, data: &[u8]) -> anyhow::Result<()> {
+        self.send_reliable(data).await
     }
     async fn recv(&self) -> anyhow::Result<Vec<u8>> {
         self.recv().await
@@ -141,32 +1111,194 @@ impl Connection for EspNowChannel {
 
 impl EspNowChannel {
     pub fn is_initializer(&self) -> bool {
-        get_mac() > self.addr
+        self.espnow.id > self.id
+    }
+    fn next_msg_id(&self) -> u16 {
+        let msg_id = self.next_msg_id.get();
+        self.next_msg_id.set(msg_id.wrapping_add(1));
+        msg_id
+    }
+    pub async fn send(&self, data: &[u8]) -> Result<()> {
+        self.send_fragments(self.next_msg_id(), false, data).await
     }
-    pub fn send(&self, data: &[u8]) -> Result<()> {
-        self.espnow
-            .send(self.addr, &postcard::to_allocvec(&Some(data))?)?;
+    // Retransmits the whole message with doubling timeouts until the peer
+    // acknowledges it.
+    pub async fn send_reliable(&self, data: &[u8]) -> Result<()> {
+        let msg_id = self.next_msg_id();
+        let (tx, rx) = bounded(1);
+        self.espnow.acks.insert((self.id.clone(), msg_id), tx);
+        let started = Instant::now();
+        let result = async {
+            for attempt in 0..=RETRANSMITS {
+                self.send_fragments(msg_id, true, data).await?;
+                let acked = async { rx.recv().await.is_ok() };
+                let timeout = async {
+                    futures_timer::Delay::new(ACK_TIMEOUT * 2u32.pow(attempt)).await;
+                    false
+                };
+                if or(acked, timeout).await {
+                    self.espnow.record_latency(&self.id, started.elapsed());
+                    return Ok(());
+                }
+            }
+            bail!("peer {} is unreachable", self.id.to_base58())
+        }
+        .await;
+        self.espnow.acks.remove(&(self.id.clone(), msg_id));
+        result
+    }
+    async fn send_fragments(&self, msg_id: u16, reliable: bool, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_MESSAGE_LEN {
+            bail!("message of {} bytes is too large", data.len());
+        }
+        let total = data.len().div_ceil(FRAGMENT_LEN).max(1) as u8;
+        let mut chunks = data.chunks(FRAGMENT_LEN);
+        for index in 0..total {
+            let payload = Payload::Fragment {
+                msg_id,
+                index,
+                total,
+                reliable,
+                data: chunks.next().unwrap_or_default(),
+            };
+            self.send_payload(&postcard::to_allocvec(&payload)?).await?;
+        }
         Ok(())
     }
+    // The driver's send queue is short; back off briefly when it is full
+    // instead of dropping the rest of a fragmented message.
+    async fn send_payload(&self, payload: &[u8]) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            match self.espnow.send_routed(&self.id, payload) {
+                Ok(()) => return Ok(()),
+                Err(e) if retries >= SEND_RETRIES => return Err(e),
+                Err(_) => {
+                    retries += 1;
+                    futures_timer::Delay::new(Duration::from_millis(10 * retries as u64)).await;
+                }
+            }
+        }
+    }
     pub async fn recv(&self) -> Result<Vec<u8>> {
         loop {
             let recv = self.rx.recv().await?;
-            if let Some(vec) = postcard::from_bytes(&recv)? {
-                break Ok(vec);
+            if let Ok(Payload::Fragment {
+                msg_id,
+                index,
+                total,
+                reliable,
+                data,
+            }) = postcard::from_bytes(&recv)
+            {
+                let message = self
+                    .reassembler
+                    .borrow_mut()
+                    .push(msg_id, index, total, data);
+                let Some(message) = message else {
+                    continue;
+                };
+                if !reliable {
+                    break Ok(message);
+                }
+                // Ack retransmissions too: the first ack may have been lost.
+                let ack = postcard::to_allocvec(&Payload::Ack { msg_id })?;
+                self.send_payload(&ack).await.ok();
+                let mut delivered = self.delivered.borrow_mut();
+                if delivered.contains(&msg_id) {
+                    continue;
+                }
+                if delivered.len() >= DEDUP_WINDOW {
+                    delivered.pop_front();
+                }
+                delivered.push_back(msg_id);
+                break Ok(message);
             }
         }
     }
-    pub fn send_json(&self, data: &impl Serialize) -> Result<()> {
+    pub async fn send_json(&self, data: &impl Serialize) -> Result<()> {
         let vec = serde_json::to_vec(data)?;
-        self.send(&vec)?;
-        Ok(())
+        self.send(&vec).await
     }
     pub async fn recv_json<T: DeserializeOwned>(&self) -> Result<T> {
         let vec = self.recv().await?;
         let dat = serde_json::from_slice(&vec)?;
         Ok(dat)
     }
-    pub fn addr(&self) -> [u8; 6] {
-        self.addr
+}
+
+impl Schema for EspNowService {
+    fn get_schema(&self) -> DataSchema {
+        let cap = DataSchema {
+            id: self.peer_cap.get_key().to_string(),
+            title: Some(String::from("Peer limit")),
+            local_only: true,
+            detail: DetailDataSchema::Integer {
+                minimum: Some(2),
+                maximum: Some(MAX_PEERS as i64),
+            },
+            ..Default::default()
+        };
+        let network_key = DataSchema {
+            id: self.network_key.get_key().to_string(),
+            title: Some(String::from("Network key")),
+            description: Some(String::from(
+                "Shared by every thing of this installation; protects link keys",
+            )),
+            write_only: true,
+            local_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let links = self
+            .peers
+            .as_ref()
+            .borrow()
+            .addrs()
+            .into_iter()
+            .map(|addr| DataSchema {
+                id: Self::link_property(&addr),
+                title: Some(format!("Link to {}", addr.to_base58())),
+                read_only: true,
+                detail: DetailDataSchema::String,
+                ..Default::default()
+            })
+            .map(|field| (field.id.clone(), field))
+            .collect();
+        let links = DataSchema {
+            id: String::from("espnow_links"),
+            title: Some(String::from("Links")),
+            detail: DetailDataSchema::Object { properties: links },
+            ..Default::default()
+        };
+        let mut map = BTreeMap::new();
+        map.insert(cap.id.clone(), cap);
+        map.insert(links.id.clone(), links);
+        map.insert(network_key.id.clone(), network_key);
+        for (entry, title) in [
+            (&self.peer_count, "Registered peers"),
+            (&self.peers_added, "Peers added"),
+            (&self.peers_evicted, "Peers evicted"),
+            (&self.peers_rejected, "Peers rejected"),
+            (&self.encrypted_peers, "Encrypted peers"),
+        ] {
+            let field = DataSchema {
+                id: entry.get_key().to_string(),
+                title: Some(String::from(title)),
+                read_only: true,
+                detail: DetailDataSchema::Integer {
+                    minimum: None,
+                    maximum: None,
+                },
+                ..Default::default()
+            };
+            map.insert(field.id.clone(), field);
+        }
+        DataSchema {
+            id: String::from("espnow"),
+            title: Some(String::from("ESP-NOW")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
     }
 }
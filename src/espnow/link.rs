@@ -0,0 +1,132 @@
+#[cfg(target_os = "espidf")]
+use core::ffi::c_void;
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+// Radio callbacks run on the Wi-Fi task, so the table lives behind a lock
+// rather than in the service's `Rc`s. It only holds registered peers, so
+// foreign ESP-NOW devices nearby cannot fill it.
+static LINKS: Mutex<BTreeMap<[u8; 6], LinkStats>> = Mutex::new(BTreeMap::new());
+const MAX_LINKS: usize = 32;
+
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct LinkStats {
+    pub sent: u32,
+    pub failed: u32,
+    pub received: u32,
+    pub dropped: u32,
+    pub rssi: Option<i8>,
+    pub latency_ms: Option<u32>,
+}
+
+impl LinkStats {
+    // Smoothed like TCP's SRTT so one retransmission does not swamp it.
+    fn record_latency(&mut self, latency: Duration) {
+        let sample = latency.as_millis().min(u32::MAX as u128) as u32;
+        self.latency_ms = Some(match self.latency_ms {
+            Some(avg) => avg - avg / 8 + sample / 8,
+            None => sample,
+        });
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "sent {}, failed {}, received {}, dropped {}",
+            self.sent, self.failed, self.received, self.dropped
+        );
+        if let Some(rssi) = self.rssi {
+            summary.push_str(&format!(", {rssi} dBm"));
+        }
+        if let Some(latency) = self.latency_ms {
+            summary.push_str(&format!(", {latency} ms"));
+        }
+        summary
+    }
+}
+
+fn update(addr: [u8; 6], f: impl FnOnce(&mut LinkStats)) {
+    if let Some(link) = LINKS.lock().ok().as_mut().and_then(|l| l.get_mut(&addr)) {
+        f(link);
+    }
+}
+
+pub fn track(addr: [u8; 6]) {
+    let Ok(mut links) = LINKS.lock() else {
+        return;
+    };
+    if links.len() < MAX_LINKS {
+        links.entry(addr).or_default();
+    }
+}
+
+pub fn forget(addr: &[u8; 6]) {
+    if let Ok(mut links) = LINKS.lock() {
+        links.remove(addr);
+    }
+}
+
+pub fn record_send(addr: [u8; 6], success: bool) {
+    update(addr, |link| match success {
+        true => link.sent += 1,
+        false => link.failed += 1,
+    });
+}
+
+pub fn record_received(addr: [u8; 6]) {
+    update(addr, |link| link.received += 1);
+}
+
+pub fn record_dropped(addr: [u8; 6]) {
+    update(addr, |link| link.dropped += 1);
+}
+
+pub fn record_latency(addr: [u8; 6], latency: Duration) {
+    update(addr, |link| link.record_latency(latency));
+}
+
+pub fn get(addr: &[u8; 6]) -> LinkStats {
+    LINKS
+        .lock()
+        .ok()
+        .and_then(|links| links.get(addr).copied())
+        .unwrap_or_default()
+}
+
+// ESP-NOW frames are vendor specific action frames; category 127 follows
+// the 24 byte header and the sender is the second address.
+#[cfg(target_os = "espidf")]
+const ACTION_FRAME: u8 = 0xd0;
+#[cfg(target_os = "espidf")]
+const VENDOR_SPECIFIC: u8 = 127;
+
+#[cfg(target_os = "espidf")]
+unsafe extern "C" fn sniff(buf: *mut c_void, kind: esp_idf_sys::wifi_promiscuous_pkt_type_t) {
+    if kind != esp_idf_sys::wifi_promiscuous_pkt_type_t_WIFI_PKT_MGMT {
+        return;
+    }
+    let packet = &*(buf as *const esp_idf_sys::wifi_promiscuous_pkt_t);
+    if (packet.rx_ctrl.sig_len() as usize) < 25 {
+        return;
+    }
+    let frame = std::slice::from_raw_parts(packet.payload.as_ptr(), 25);
+    if frame[0] != ACTION_FRAME || frame[24] != VENDOR_SPECIFIC {
+        return;
+    }
+    let mut addr = [0u8; 6];
+    addr.copy_from_slice(&frame[10..16]);
+    let rssi = packet.rx_ctrl.rssi() as i8;
+    update(addr, |link| link.rssi = Some(rssi));
+}
+
+// The receive callback does not carry RSSI, so management frames are
+// sniffed alongside normal operation to read it.
+#[cfg(target_os = "espidf")]
+pub fn start_sniffer() -> anyhow::Result<()> {
+    let filter = esp_idf_sys::wifi_promiscuous_filter_t {
+        filter_mask: esp_idf_sys::WIFI_PROMIS_FILTER_MASK_MGMT,
+    };
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_set_promiscuous_filter(&filter) })?;
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_set_promiscuous_rx_cb(Some(sniff)) })?;
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_set_promiscuous(true) })?;
+    Ok(())
+}
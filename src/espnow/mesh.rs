@@ -0,0 +1,101 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+pub const MAX_HOPS: u8 = 4;
+const ROUTE_TIMEOUT: Duration = Duration::from_secs(15);
+// Keeps an advertisement inside a single ESP-NOW frame.
+const MAX_ADVERTISED_ROUTES: usize = 12;
+const SEEN_WINDOW: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    pub via: [u8; 6],
+    pub hops: u8,
+    seen: Instant,
+}
+
+// Distance-vector table fed by neighbour advertisements, plus the recent
+// (source, sequence) pairs used to stop flooded frames from looping.
+#[derive(Default)]
+pub struct RouteTable {
+    routes: BTreeMap<Vec<u8>, Route>,
+    seen: VecDeque<(Vec<u8>, u32)>,
+}
+
+impl RouteTable {
+    fn expire(&mut self) {
+        self.routes
+            .retain(|_, route| route.seen.elapsed() < ROUTE_TIMEOUT);
+    }
+
+    // A fresher report through the same neighbour always wins, so routes
+    // that grew longer or vanished upstream are not kept alive by old data.
+    pub fn offer(&mut self, dest: &[u8], via: [u8; 6], hops: u8) {
+        if hops == 0 || hops > MAX_HOPS {
+            return;
+        }
+        let route = Route {
+            via,
+            hops,
+            seen: Instant::now(),
+        };
+        match self.routes.get_mut(dest) {
+            Some(old)
+                if old.via != via && old.hops <= hops && old.seen.elapsed() < ROUTE_TIMEOUT => {}
+            Some(old) => *old = route,
+            None => {
+                self.routes.insert(dest.to_vec(), route);
+            }
+        }
+    }
+
+    pub fn learn(&mut self, own_id: &[u8], neighbor: [u8; 6], id: &[u8], routes: &[(&[u8], u8)]) {
+        if id == own_id {
+            return;
+        }
+        self.offer(id, neighbor, 1);
+        for (dest, hops) in routes {
+            if *dest != own_id && *dest != id {
+                self.offer(dest, neighbor, hops.saturating_add(1));
+            }
+        }
+    }
+
+    pub fn next_hop(&mut self, dest: &[u8]) -> Option<[u8; 6]> {
+        self.expire();
+        self.routes.get(dest).map(|route| route.via)
+    }
+
+    pub fn advertised(&mut self) -> Vec<(Vec<u8>, u8)> {
+        self.expire();
+        let mut routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|(dest, route)| (dest.clone(), route.hops))
+            .collect();
+        routes.sort_by_key(|(_, hops)| *hops);
+        routes.truncate(MAX_ADVERTISED_ROUTES);
+        routes
+    }
+
+    pub fn routes(&mut self) -> Vec<(Vec<u8>, Route)> {
+        self.expire();
+        self.routes
+            .iter()
+            .map(|(dest, route)| (dest.clone(), *route))
+            .collect()
+    }
+
+    pub fn is_duplicate(&mut self, src: &[u8], seq: u32) -> bool {
+        if self.seen.iter().any(|(s, q)| *q == seq && s == src) {
+            return true;
+        }
+        if self.seen.len() >= SEEN_WINDOW {
+            self.seen.pop_front();
+        }
+        self.seen.push_back((src.to_vec(), seq));
+        false
+    }
+}
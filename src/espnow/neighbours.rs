@@ -0,0 +1,85 @@
+use blake2::{digest::Mac, Blake2sMac256};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+pub const NONCE_LEN: usize = 16;
+// Proven again now and then, so an address that changed hands stops
+// counting.
+const VERIFIED_TIMEOUT: Duration = Duration::from_secs(600);
+const CHALLENGE_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Challenge {
+    id: Vec<u8>,
+    nonce: [u8; NONCE_LEN],
+    sent: Instant,
+}
+
+// Adverts only claim an id. The address behind a claim is trusted for
+// routing once it has answered a fresh nonce with a tag that only the
+// claimed thing and we can make, from the key both derive from the pinned
+// public keys.
+#[derive(Default)]
+pub struct Neighbours {
+    verified: BTreeMap<[u8; 6], (Vec<u8>, Instant)>,
+    challenges: BTreeMap<[u8; 6], Challenge>,
+}
+
+impl Neighbours {
+    pub fn id(&mut self, addr: &[u8; 6]) -> Option<Vec<u8>> {
+        self.verified
+            .retain(|_, (_, verified)| verified.elapsed() < VERIFIED_TIMEOUT);
+        self.verified.get(addr).map(|(id, _)| id.clone())
+    }
+
+    // Whether to send the nonce: not for a claim already proven or one
+    // challenged a moment ago.
+    pub fn challenge(&mut self, addr: [u8; 6], id: &[u8], nonce: [u8; NONCE_LEN]) -> bool {
+        if self.id(&addr).as_deref() == Some(id) {
+            return false;
+        }
+        if let Some(challenge) = self.challenges.get(&addr) {
+            if challenge.id == id && challenge.sent.elapsed() < CHALLENGE_INTERVAL {
+                return false;
+            }
+        }
+        self.challenges.insert(
+            addr,
+            Challenge {
+                id: id.to_vec(),
+                nonce,
+                sent: Instant::now(),
+            },
+        );
+        true
+    }
+
+    pub fn is_pending(&self, addr: &[u8; 6], id: &[u8], nonce: &[u8; NONCE_LEN]) -> bool {
+        self.challenges.get(addr).is_some_and(|challenge| {
+            challenge.id == id
+                && challenge.nonce == *nonce
+                && challenge.sent.elapsed() < CHALLENGE_INTERVAL
+        })
+    }
+
+    pub fn verify(&mut self, addr: [u8; 6], id: &[u8]) {
+        self.challenges.remove(&addr);
+        self.verified.insert(addr, (id.to_vec(), Instant::now()));
+    }
+}
+
+pub fn proof(
+    key: &[u8; 32],
+    prover: &[u8],
+    verifier: &[u8],
+    nonce: &[u8; NONCE_LEN],
+) -> Blake2sMac256 {
+    let mut mac = <Blake2sMac256 as Mac>::new_from_slice(key).expect("32 byte key");
+    for part in [prover, verifier] {
+        mac.update(&(part.len() as u16).to_le_bytes());
+        mac.update(part);
+    }
+    mac.update(nonce);
+    mac
+}
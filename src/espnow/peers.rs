@@ -0,0 +1,110 @@
+use super::link::{self, LinkStats};
+use base58::ToBase58;
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Instant};
+
+#[cfg(target_os = "espidf")]
+pub const MAX_PEERS: u32 = esp_idf_sys::ESP_NOW_MAX_TOTAL_PEER_NUM;
+#[cfg(target_os = "espidf")]
+pub const MAX_ENCRYPTED_PEERS: u32 = esp_idf_sys::ESP_NOW_MAX_ENCRYPT_PEER_NUM;
+// The driver's defaults, for the simulated radio.
+#[cfg(not(target_os = "espidf"))]
+pub const MAX_PEERS: u32 = 20;
+#[cfg(not(target_os = "espidf"))]
+pub const MAX_ENCRYPTED_PEERS: u32 = 6;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerMetrics {
+    pub added: u32,
+    pub evicted: u32,
+    pub rejected: u32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerStatus {
+    pub addr: String,
+    pub encrypted: bool,
+    pub idle_secs: u64,
+    pub link: LinkStats,
+}
+
+struct Slot {
+    last_used: Instant,
+    encrypted: bool,
+}
+
+// Mirrors what is registered with the driver, with the last time each peer
+// was used so the stalest can make room for a new one.
+#[derive(Default)]
+pub struct PeerTable {
+    slots: BTreeMap<[u8; 6], Slot>,
+    pub metrics: PeerMetrics,
+}
+
+impl PeerTable {
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn contains(&self, addr: &[u8; 6]) -> bool {
+        self.slots.contains_key(addr)
+    }
+
+    pub fn touch(&mut self, addr: [u8; 6]) {
+        if let Some(slot) = self.slots.get_mut(&addr) {
+            slot.last_used = Instant::now();
+        }
+    }
+
+    pub fn insert(&mut self, addr: [u8; 6], encrypted: bool) {
+        self.slots.insert(
+            addr,
+            Slot {
+                last_used: Instant::now(),
+                encrypted,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, addr: &[u8; 6]) -> bool {
+        self.slots.remove(addr).is_some()
+    }
+
+    pub fn addrs(&self) -> Vec<[u8; 6]> {
+        self.slots.keys().copied().collect()
+    }
+
+    pub fn is_encrypted(&self, addr: &[u8; 6]) -> bool {
+        self.slots.get(addr).is_some_and(|slot| slot.encrypted)
+    }
+
+    pub fn set_encrypted(&mut self, addr: &[u8; 6], encrypted: bool) {
+        if let Some(slot) = self.slots.get_mut(addr) {
+            slot.encrypted = encrypted;
+        }
+    }
+
+    pub fn encrypted_count(&self) -> usize {
+        self.slots.values().filter(|slot| slot.encrypted).count()
+    }
+
+    pub fn status(&self) -> Vec<PeerStatus> {
+        self.slots
+            .iter()
+            .map(|(addr, slot)| PeerStatus {
+                addr: addr.to_base58(),
+                encrypted: slot.encrypted,
+                idle_secs: slot.last_used.elapsed().as_secs(),
+                link: link::get(addr),
+            })
+            .collect()
+    }
+
+    pub fn victim(&self, pinned: impl Fn(&[u8; 6]) -> bool) -> Option<[u8; 6]> {
+        self.slots
+            .iter()
+            .filter(|(addr, _)| !pinned(addr))
+            .min_by_key(|(_, slot)| slot.last_used)
+            .map(|(addr, _)| *addr)
+    }
+}
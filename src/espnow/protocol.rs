@@ -0,0 +1,103 @@
+use crate::data_schema::ThingSchema;
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// Schemas and property values lean on serde features postcard cannot
+// decode (untagged, flatten), so they travel as JSON text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> Serialize for Json<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let text = serde_json::to_string(&self.0).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&text)
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Json<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        serde_json::from_str(&text)
+            .map(Json)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Schema,
+    Read,
+    Write,
+    Notify,
+    Invoke,
+    Relay,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    Hello {
+        title: String,
+        capabilities: Vec<Capability>,
+    },
+    GetSchema,
+    Schema(Json<ThingSchema>),
+    Read {
+        key: String,
+    },
+    Write {
+        key: String,
+        value: Json<Value>,
+    },
+    Notify {
+        key: String,
+        value: Json<Value>,
+    },
+    Invoke {
+        action: String,
+        input: Json<Value>,
+    },
+    Value(Json<Value>),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Request(u32),
+    Response(u32),
+    Event,
+}
+
+// `version` comes first so a peer on another protocol revision can be told
+// apart from a corrupt frame without decoding the rest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub version: u8,
+    pub kind: Kind,
+    pub message: Message,
+}
+
+#[async_trait::async_trait(?Send)]
+pub trait MessageHandler {
+    async fn handle_request(&self, peer: &str, message: Message) -> Message;
+    async fn handle_event(&self, peer: &str, message: Message);
+}
+
+pub fn encode(kind: Kind, message: Message) -> Result<Vec<u8>> {
+    let envelope = Envelope {
+        version: PROTOCOL_VERSION,
+        kind,
+        message,
+    };
+    Ok(postcard::to_allocvec(&envelope)?)
+}
+
+pub fn decode(data: &[u8]) -> Result<Envelope> {
+    match data.first() {
+        Some(&PROTOCOL_VERSION) => Ok(postcard::from_bytes(data)?),
+        Some(version) => bail!("unsupported protocol version {version}"),
+        None => bail!("empty message"),
+    }
+}
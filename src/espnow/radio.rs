@@ -0,0 +1,132 @@
+#[cfg(target_os = "espidf")]
+use super::link;
+use anyhow::Result;
+#[cfg(target_os = "espidf")]
+use async_channel::bounded;
+use async_channel::Receiver;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::espnow::{EspNow, SendStatus};
+
+pub const BROADCAST: [u8; 6] = [0xff; 6];
+// ESP_NOW_MAX_DATA_LEN, which the host has no bindings for.
+pub const MAX_DATA_LEN: usize = 250;
+#[cfg(target_os = "espidf")]
+const RADIO_QUEUE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interface {
+    Station,
+    AccessPoint,
+}
+
+pub struct PeerConfig {
+    pub addr: [u8; 6],
+    pub channel: u8,
+    pub interface: Interface,
+    pub lmk: Option<[u8; 16]>,
+}
+
+// Where Wi-Fi has put the radio: ESP-NOW shares its channel and, once the
+// station is up, goes out on its interface.
+pub trait Uplink {
+    fn channel(&self) -> Result<u8>;
+    fn interface(&self) -> Interface;
+}
+
+// What `EspNowService` needs from the air: frames in, frames out, and the
+// driver's peer list. Everything above it is radio agnostic.
+pub trait Radio {
+    fn send(&self, addr: [u8; 6], data: &[u8]) -> Result<()>;
+    fn broadcast(&self, data: &[u8]) -> Result<()> {
+        self.send(BROADCAST, data)
+    }
+    // Every frame heard, with the sender's address.
+    fn frames(&self) -> Receiver<([u8; 6], Vec<u8>)>;
+    // Adds the peer, or updates it if already known.
+    fn set_peer(&self, peer: &PeerConfig) -> Result<()>;
+    fn remove_peer(&self, addr: [u8; 6]) -> Result<()>;
+    fn set_pmk(&self, pmk: &[u8; 16]) -> Result<()>;
+    fn set_channel(&self, channel: u8) -> Result<()>;
+    // For nonces and sequence numbers; the hardware RNG on the device.
+    fn random(&self) -> u32;
+}
+
+#[cfg(target_os = "espidf")]
+pub struct EspNowRadio {
+    espnow: EspNow,
+    frames: Receiver<([u8; 6], Vec<u8>)>,
+}
+
+#[cfg(target_os = "espidf")]
+impl EspNowRadio {
+    pub fn new() -> Result<Self> {
+        let espnow = EspNow::take()?;
+        let (tx, frames) = bounded(RADIO_QUEUE);
+        espnow.register_recv_cb(move |addr, data| {
+            if let Ok(addr) = addr.try_into() {
+                if tx.try_send((addr, data.to_vec())).is_err() {
+                    link::record_dropped(addr);
+                }
+            }
+        })?;
+        espnow.register_send_cb(|addr, status| {
+            if let Ok(addr) = addr.try_into() {
+                link::record_send(addr, status == SendStatus::SUCCESS);
+            }
+        })?;
+        if let Err(e) = link::start_sniffer() {
+            println!("espnow rssi is unavailable: {e}");
+        }
+        Ok(Self { espnow, frames })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Radio for EspNowRadio {
+    fn send(&self, addr: [u8; 6], data: &[u8]) -> Result<()> {
+        self.espnow.send(addr, data)?;
+        Ok(())
+    }
+    fn frames(&self) -> Receiver<([u8; 6], Vec<u8>)> {
+        self.frames.clone()
+    }
+    fn set_peer(&self, peer: &PeerConfig) -> Result<()> {
+        let info = esp_idf_sys::esp_now_peer_info {
+            peer_addr: peer.addr,
+            channel: peer.channel,
+            ifidx: match peer.interface {
+                Interface::Station => esp_idf_sys::esp_interface_t_ESP_IF_WIFI_STA,
+                Interface::AccessPoint => esp_idf_sys::esp_interface_t_ESP_IF_WIFI_AP,
+            },
+            encrypt: peer.lmk.is_some(),
+            lmk: peer.lmk.unwrap_or_default(),
+            ..Default::default()
+        };
+        if self.espnow.peer_exists(peer.addr)? {
+            self.espnow.mod_peer(info)?;
+        } else {
+            self.espnow.add_peer(info)?;
+        }
+        Ok(())
+    }
+    fn remove_peer(&self, addr: [u8; 6]) -> Result<()> {
+        self.espnow.del_peer(addr)?;
+        Ok(())
+    }
+    fn set_pmk(&self, pmk: &[u8; 16]) -> Result<()> {
+        self.espnow.set_pmk(pmk)?;
+        Ok(())
+    }
+    fn set_channel(&self, channel: u8) -> Result<()> {
+        esp_idf_sys::esp!(unsafe {
+            esp_idf_sys::esp_wifi_set_channel(
+                channel,
+                esp_idf_sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE,
+            )
+        })?;
+        Ok(())
+    }
+    fn random(&self) -> u32 {
+        unsafe { esp_idf_sys::esp_random() }
+    }
+}
@@ -0,0 +1,348 @@
+use super::link;
+use super::radio::{Interface, PeerConfig, Radio, Uplink, BROADCAST, MAX_DATA_LEN};
+use anyhow::{bail, Result};
+use async_channel::{bounded, Receiver, Sender};
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeSet,
+    rc::Rc,
+};
+
+const SIM_QUEUE: usize = 32;
+
+// A sender and a receiver that cannot hear it.
+type Gap = ([u8; 6], [u8; 6]);
+
+struct Node {
+    addr: [u8; 6],
+    channel: Rc<Cell<u8>>,
+    tx: Sender<([u8; 6], Vec<u8>)>,
+}
+
+// A shared radio medium for running several nodes in one process: every
+// frame reaches each node on the same channel and in range unless the dice
+// say it is lost. Unicast needs the peer to be registered, as with the real
+// driver.
+#[derive(Clone)]
+pub struct SimMedium {
+    nodes: Rc<RefCell<Vec<Node>>>,
+    out_of_range: Rc<RefCell<BTreeSet<Gap>>>,
+    loss: Rc<Cell<f32>>,
+    rng: Rc<Cell<u32>>,
+}
+
+impl SimMedium {
+    pub fn new(loss: f32, seed: u32) -> Self {
+        Self {
+            nodes: Rc::new(RefCell::new(Vec::new())),
+            out_of_range: Rc::new(RefCell::new(BTreeSet::new())),
+            loss: Rc::new(Cell::new(loss)),
+            rng: Rc::new(Cell::new(seed.max(1))),
+        }
+    }
+
+    pub fn set_loss(&self, loss: f32) {
+        self.loss.set(loss);
+    }
+
+    // Both ways, so the two only reach each other through a third node.
+    pub fn separate(&self, a: [u8; 6], b: [u8; 6]) {
+        let mut out_of_range = self.out_of_range.borrow_mut();
+        out_of_range.insert((a, b));
+        out_of_range.insert((b, a));
+    }
+
+    pub fn attach(&self, addr: [u8; 6], channel: u8) -> SimRadio {
+        let (tx, frames) = bounded(SIM_QUEUE);
+        let channel = Rc::new(Cell::new(channel));
+        self.nodes.borrow_mut().push(Node {
+            addr,
+            channel: channel.clone(),
+            tx,
+        });
+        SimRadio {
+            medium: self.clone(),
+            addr,
+            channel,
+            peers: RefCell::new(BTreeSet::new()),
+            frames,
+        }
+    }
+
+    // xorshift32; deterministic for a given seed so failures replay.
+    fn random(&self) -> u32 {
+        let mut x = self.rng.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng.set(x);
+        x
+    }
+
+    fn lost(&self) -> bool {
+        (self.random() as f32 / u32::MAX as f32) < self.loss.get()
+    }
+
+    // Whether a unicast frame arrived, standing in for the driver's ack.
+    fn transmit(&self, src: [u8; 6], channel: u8, dst: [u8; 6], data: &[u8]) -> bool {
+        let mut delivered = false;
+        for node in self.nodes.borrow().iter() {
+            if node.addr == src
+                || node.channel.get() != channel
+                || self.out_of_range.borrow().contains(&(src, node.addr))
+            {
+                continue;
+            }
+            if dst != BROADCAST && dst != node.addr {
+                continue;
+            }
+            if self.lost() {
+                continue;
+            }
+            delivered |= node.tx.try_send((src, data.to_vec())).is_ok();
+        }
+        delivered
+    }
+}
+
+pub struct SimRadio {
+    medium: SimMedium,
+    addr: [u8; 6],
+    channel: Rc<Cell<u8>>,
+    peers: RefCell<BTreeSet<[u8; 6]>>,
+    frames: Receiver<([u8; 6], Vec<u8>)>,
+}
+
+impl SimRadio {
+    pub fn addr(&self) -> [u8; 6] {
+        self.addr
+    }
+
+    // Stands in for Wi-Fi: a node without a station, on its radio's channel.
+    pub fn uplink(&self) -> SimUplink {
+        SimUplink {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+pub struct SimUplink {
+    channel: Rc<Cell<u8>>,
+}
+
+impl Uplink for SimUplink {
+    fn channel(&self) -> Result<u8> {
+        Ok(self.channel.get())
+    }
+    fn interface(&self) -> Interface {
+        Interface::AccessPoint
+    }
+}
+
+impl Radio for SimRadio {
+    fn send(&self, addr: [u8; 6], data: &[u8]) -> Result<()> {
+        if !self.peers.borrow().contains(&addr) {
+            bail!("peer not found");
+        }
+        if data.len() > MAX_DATA_LEN {
+            bail!("frame of {} bytes is too long", data.len());
+        }
+        let delivered = self
+            .medium
+            .transmit(self.addr, self.channel.get(), addr, data);
+        if addr != BROADCAST {
+            link::record_send(addr, delivered);
+        }
+        Ok(())
+    }
+    fn frames(&self) -> Receiver<([u8; 6], Vec<u8>)> {
+        self.frames.clone()
+    }
+    fn set_peer(&self, peer: &PeerConfig) -> Result<()> {
+        self.peers.borrow_mut().insert(peer.addr);
+        Ok(())
+    }
+    fn remove_peer(&self, addr: [u8; 6]) -> Result<()> {
+        if !self.peers.borrow_mut().remove(&addr) {
+            bail!("peer not found");
+        }
+        Ok(())
+    }
+    fn set_pmk(&self, _pmk: &[u8; 16]) -> Result<()> {
+        Ok(())
+    }
+    fn set_channel(&self, channel: u8) -> Result<()> {
+        self.channel.set(channel);
+        Ok(())
+    }
+    fn random(&self) -> u32 {
+        self.medium.random()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::espnow::{EspNowChannel, EspNowService};
+    use crate::identity::Identity;
+    use crate::pairing::PairingService;
+    use crate::storage::{MemoryNvs, StorageService};
+    use crate::topics::TopicService;
+    use async_executor::LocalExecutor;
+    use base58::ToBase58;
+    use futures_lite::future::{block_on, or, zip};
+    use serde_json::json;
+    use std::time::Duration;
+
+    const CHANNEL: u8 = 1;
+    const ADVERTISE_INTERVAL: Duration = Duration::from_millis(50);
+    const TIMEOUT: Duration = Duration::from_secs(20);
+
+    fn addr(n: u8) -> [u8; 6] {
+        [0x02, 0, 0, 0, 0, n]
+    }
+
+    // Nodes 1..=count, every one paired with every other.
+    fn mesh(medium: &SimMedium, count: u8) -> Vec<EspNowService> {
+        let identities: Vec<Identity> = (1..=count)
+            .map(|n| Identity::load_or_insert(MemoryNvs::default(), || [n; 32]).unwrap())
+            .collect();
+        identities
+            .iter()
+            .enumerate()
+            .map(|(i, identity)| {
+                let storage = StorageService::new(MemoryNvs::default()).unwrap();
+                let paired: Vec<_> = identities
+                    .iter()
+                    .filter(|other| other.id() != identity.id())
+                    .map(|other| {
+                        json!({
+                            "id": other.name(),
+                            "public_key": other.public_key().to_base58(),
+                        })
+                    })
+                    .collect();
+                storage.set("paired_peers", json!(paired));
+                let pairing = PairingService::new(&storage);
+                let topics = TopicService::new(&storage, identity);
+                let radio = medium.attach(addr(i as u8 + 1), CHANNEL);
+                let uplink = radio.uplink();
+                EspNowService::new(
+                    Rc::new(radio),
+                    &uplink,
+                    &storage,
+                    identity,
+                    &pairing,
+                    &topics,
+                )
+                .unwrap()
+            })
+            .collect()
+    }
+
+    // Runs the nodes, advertising as the controller does, until `test`
+    // finishes or time runs out.
+    fn run<T>(nodes: &[EspNowService], test: impl std::future::Future<Output = T>) -> T {
+        let ex = LocalExecutor::new();
+        for node in nodes {
+            ex.spawn(async move {
+                let advertise = async {
+                    loop {
+                        node.advertise().ok();
+                        futures_timer::Delay::new(ADVERTISE_INTERVAL).await;
+                    }
+                };
+                or(node.run_handle(), advertise).await
+            })
+            .detach();
+        }
+        let timeout = async {
+            futures_timer::Delay::new(TIMEOUT).await;
+            panic!("simulation timed out");
+        };
+        block_on(ex.run(or(test, timeout)))
+    }
+
+    async fn wait_for(condition: impl Fn() -> bool) {
+        while !condition() {
+            futures_timer::Delay::new(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn channel_to(node: &EspNowService, id: &[u8]) -> EspNowChannel {
+        node.open_channel(BROADCAST, id);
+        loop {
+            let channel = node.next_channel().await.unwrap();
+            if channel.id == id {
+                return channel;
+            }
+        }
+    }
+
+    #[test]
+    fn routes_around_nodes_out_of_range() {
+        let medium = SimMedium::new(0.0, 1);
+        let nodes = mesh(&medium, 3);
+        medium.separate(addr(1), addr(3));
+        let (a, c) = (&nodes[0], &nodes[2]);
+        let received = run(&nodes, async {
+            wait_for(|| a.routes().iter().any(|(id, _)| id == c.id())).await;
+            let route = a.routes().into_iter().find(|(id, _)| id == c.id());
+            assert_eq!(route.map(|(_, r)| (r.via, r.hops)), Some((addr(2), 2)));
+            let to_c = channel_to(a, c.id()).await;
+            let from_a = channel_to(c, a.id()).await;
+            let (sent, received) = zip(to_c.send_reliable(b"over the middle"), from_a.recv()).await;
+            sent.unwrap();
+            received.unwrap()
+        });
+        assert_eq!(received, b"over the middle");
+    }
+
+    #[test]
+    fn reassembles_messages_larger_than_a_frame() {
+        let medium = SimMedium::new(0.0, 2);
+        let nodes = mesh(&medium, 2);
+        let (a, b) = (&nodes[0], &nodes[1]);
+        let message: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        let received = run(&nodes, async {
+            wait_for(|| a.neighbour(&addr(2)).is_some()).await;
+            let to_b = channel_to(a, b.id()).await;
+            let from_a = channel_to(b, a.id()).await;
+            let (sent, received) = zip(to_b.send_reliable(&message), from_a.recv()).await;
+            sent.unwrap();
+            received.unwrap()
+        });
+        assert_eq!(received, message);
+    }
+
+    #[test]
+    fn delivers_each_reliable_message_once_under_loss() {
+        let medium = SimMedium::new(0.0, 3);
+        let nodes = mesh(&medium, 2);
+        let (a, b) = (&nodes[0], &nodes[1]);
+        let received = run(&nodes, async {
+            wait_for(|| a.neighbour(&addr(2)).is_some() && b.neighbour(&addr(1)).is_some()).await;
+            let to_b = channel_to(a, b.id()).await;
+            let from_a = channel_to(b, a.id()).await;
+            medium.set_loss(0.2);
+            let received = RefCell::new(Vec::new());
+            let recv = async {
+                loop {
+                    let message = from_a.recv().await.unwrap();
+                    received.borrow_mut().push(message);
+                }
+            };
+            let send = async {
+                for i in 0..8u8 {
+                    to_b.send_reliable(&[i; 300]).await.unwrap();
+                }
+                // Retransmissions whose ack was lost are still in the air.
+                futures_timer::Delay::new(Duration::from_secs(1)).await;
+            };
+            or(recv, send).await;
+            received.into_inner()
+        });
+        let expected: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 300]).collect();
+        assert_eq!(received, expected);
+    }
+}
@@ -1,17 +1,51 @@
 use crate::controller::Controller;
-use crate::storage::StorageService;
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::device::factory::DeviceManifest;
+use crate::election::ElectionService;
+use crate::pairing::PairingService;
+use crate::rules::{Rule, RuleEngine};
+use crate::scenes::SceneService;
+use crate::storage::{StorageEntry, StorageService};
 use crate::wifi::WifiService;
-use anyhow::Result;
-use http::header::HeaderName;
-use http::{HeaderValue, Method, Response};
+use anyhow::{anyhow, bail, Result};
+use async_executor::LocalExecutor;
+use base58::FromBase58;
+use futures_lite::future::or;
+use http::header::{self, HeaderMap, HeaderName};
+use http::{HeaderValue, Method, Request, Response, StatusCode, Version};
 use httparse::Status;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::cell::Cell;
 use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
 use std::io::{self, Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
 use std::time::Duration;
 
+// Each connection holds a socket out of lwIP's small pool, so the rest get
+// turned away instead of queueing behind a slow client.
+const MAX_CONNECTIONS: usize = 4;
+// Longest a client may go without sending anything.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest a client may take over a whole request head, however it trickles.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEAD: usize = 2048;
+const MAX_HEADERS: usize = 32;
+const READ_CHUNK: usize = 512;
+const CHUNK_SIZE: usize = 1024;
+const DEFAULT_MAX_BODY: u64 = 8192;
+const MAX_BODY_LIMIT: i64 = 65536;
+// Bodies are held whole, so all connections together may hold one of the
+// largest allowed rather than one each.
+const MAX_BODIES: usize = MAX_BODY_LIMIT as usize;
+// Requests served on one connection before it is closed, so a client that
+// pipelines forever still gives its slot back.
+const MAX_REQUESTS: usize = 32;
+
 pub async fn try_async<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
     loop {
         match f() {
@@ -22,54 +56,425 @@ pub async fn try_async<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T>
         }
     }
 }
+
+async fn timeout<T>(duration: Duration, f: impl Future<Output = Result<T>>) -> Result<T> {
+    or(f, async {
+        futures_timer::Delay::new(duration).await;
+        Err(anyhow!("timed out after {}s", duration.as_secs()))
+    })
+    .await
+}
+
+// A request the client got wrong, answered with the status before closing.
+#[derive(Debug)]
+struct HttpError(StatusCode, String);
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.0.as_u16(), self.1)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+fn http_error(status: StatusCode, message: impl Into<String>) -> anyhow::Error {
+    HttpError(status, message.into()).into()
+}
+
+// A share of a limit shared by all connections, given back however the
+// holder's task ends.
+struct Slot<'a>(&'a Cell<usize>, usize);
+
+impl<'a> Slot<'a> {
+    fn take(used: &'a Cell<usize>, amount: usize, limit: usize) -> Option<Self> {
+        if used.get() + amount > limit {
+            return None;
+        }
+        used.set(used.get() + amount);
+        Some(Self(used, amount))
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - self.1);
+    }
+}
+
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+fn keep_alive(req: &Request<()>) -> bool {
+    match req.version() {
+        Version::HTTP_10 => has_token(req.headers(), header::CONNECTION, "keep-alive"),
+        _ => !has_token(req.headers(), header::CONNECTION, "close"),
+    }
+}
+
+struct HttpConnection {
+    stream: TcpStream,
+    // Read but not yet consumed, which may run into the next pipelined
+    // request. Only read as far as needed, so it stays small.
+    buf: Vec<u8>,
+}
+
+impl HttpConnection {
+    async fn read_some(&mut self) -> Result<usize> {
+        let mut chunk = [0u8; READ_CHUNK];
+        let stream = &mut self.stream;
+        let len = timeout(IDLE_TIMEOUT, async {
+            Ok(try_async(|| stream.read(&mut chunk)).await?)
+        })
+        .await?;
+        self.buf.extend_from_slice(&chunk[..len]);
+        Ok(len)
+    }
+
+    async fn fill_to(&mut self, len: usize) -> Result<()> {
+        while self.buf.len() < len {
+            if self.read_some().await? == 0 {
+                bail!("connection closed mid request");
+            }
+        }
+        Ok(())
+    }
+
+    // None when the client closes or goes quiet between requests.
+    async fn read_head(&mut self) -> Result<Option<Request<()>>> {
+        if self.buf.is_empty() && !matches!(self.read_some().await, Ok(1..)) {
+            return Ok(None);
+        }
+        loop {
+            if let Some((req, size)) = try_parse_request(&self.buf)? {
+                self.buf.drain(..size);
+                return Ok(Some(req));
+            }
+            if self.buf.len() >= MAX_HEAD {
+                return Err(http_error(
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    "request head too large",
+                ));
+            }
+            self.fill_to(self.buf.len() + 1).await?;
+        }
+    }
+
+    // The body comes with its share of `buffered`, to hold for as long as
+    // the body is.
+    async fn read_body<'b>(
+        &mut self,
+        req: &Request<()>,
+        max: usize,
+        buffered: &'b Cell<usize>,
+    ) -> Result<(Vec<u8>, Slot<'b>)> {
+        let headers = req.headers();
+        let chunked = headers.contains_key(header::TRANSFER_ENCODING);
+        if chunked && !has_token(headers, header::TRANSFER_ENCODING, "chunked") {
+            return Err(http_error(
+                StatusCode::NOT_IMPLEMENTED,
+                "only chunked transfer encoding is supported",
+            ));
+        }
+        // Chunked framing wins over a length, as RFC 9112 says.
+        let length = match chunked {
+            true => None,
+            false => content_length(headers)?,
+        };
+        if length.is_some_and(|len| len > max) {
+            return Err(http_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("body is limited to {max} bytes"),
+            ));
+        }
+        // A chunked body only shows its size as it arrives.
+        let reserve = length.unwrap_or(if chunked { max } else { 0 });
+        let Some(slot) = Slot::take(buffered, reserve, MAX_BODIES) else {
+            return Err(http_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too many request bodies in flight",
+            ));
+        };
+        if !chunked && length.is_none() {
+            return Ok((Vec::new(), slot));
+        }
+        if has_token(headers, header::EXPECT, "100-continue") && self.buf.is_empty() {
+            write_all(&mut self.stream, b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+        let body = match length {
+            Some(len) => {
+                self.fill_to(len).await?;
+                self.buf.drain(..len).collect()
+            }
+            None => self.read_chunked(max).await?,
+        };
+        Ok((body, slot))
+    }
+
+    async fn read_chunked(&mut self, max: usize) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let (start, size) = match httparse::parse_chunk_size(&self.buf) {
+                Ok(Status::Complete(chunk)) => chunk,
+                Ok(Status::Partial) if self.buf.len() < MAX_HEAD => {
+                    self.fill_to(self.buf.len() + 1).await?;
+                    continue;
+                }
+                _ => return Err(http_error(StatusCode::BAD_REQUEST, "invalid chunk size")),
+            };
+            if body.len() as u64 + size > max as u64 {
+                return Err(http_error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("body is limited to {max} bytes"),
+                ));
+            }
+            if size == 0 {
+                self.buf.drain(..start);
+                break;
+            }
+            let end = start + size as usize;
+            self.fill_to(end + 2).await?;
+            if &self.buf[end..end + 2] != b"\r\n" {
+                return Err(http_error(StatusCode::BAD_REQUEST, "invalid chunk"));
+            }
+            body.extend_from_slice(&self.buf[start..end]);
+            self.buf.drain(..end + 2);
+        }
+        // Trailers are read and ignored, up to the empty line.
+        loop {
+            match self.buf.windows(2).position(|w| w == b"\r\n") {
+                Some(0) => {
+                    self.buf.drain(..2);
+                    return Ok(body);
+                }
+                Some(end) => {
+                    self.buf.drain(..end + 2);
+                }
+                None if self.buf.len() < MAX_HEAD => self.fill_to(self.buf.len() + 1).await?,
+                None => return Err(http_error(StatusCode::BAD_REQUEST, "trailer too large")),
+            }
+        }
+    }
+
+    async fn respond(&mut self, res: Response<Vec<u8>>, chunked: bool, close: bool) -> Result<()> {
+        // A client that stops reading would otherwise hold its slot forever.
+        timeout(
+            WRITE_TIMEOUT,
+            write_respond(&mut self.stream, res, chunked, close),
+        )
+        .await
+    }
+
+    // Answers a malformed request before giving up on the connection.
+    async fn fail(&mut self, e: anyhow::Error) -> Result<()> {
+        if let Some(HttpError(status, message)) = e.downcast_ref::<HttpError>() {
+            let res = Response::builder()
+                .status(*status)
+                .body(message.clone().into_bytes())?;
+            self.respond(res, false, true).await.ok();
+        }
+        Err(e)
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Result<Option<usize>> {
+    let mut lengths = headers.get_all(header::CONTENT_LENGTH).iter();
+    let Some(value) = lengths.next() else {
+        return Ok(None);
+    };
+    let len = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| http_error(StatusCode::BAD_REQUEST, "invalid Content-Length"))?;
+    if lengths.any(|other| other != value) {
+        return Err(http_error(
+            StatusCode::BAD_REQUEST,
+            "conflicting Content-Length",
+        ));
+    }
+    Ok(Some(len))
+}
+
+fn json<T: Serialize>(value: &T) -> Result<Response<Vec<u8>>> {
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(value)?)?)
+}
+
+fn empty() -> Response<Vec<u8>> {
+    Response::new(Vec::new())
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Result<Response<Vec<u8>>> {
+    Ok(Response::builder()
+        .status(status)
+        .body(message.into().into_bytes())?)
+}
+
+fn query<'de, T: Deserialize<'de>>(req: &'de Request<()>) -> Result<T> {
+    let query = req.uri().query().unwrap_or("");
+    serde_qs::from_str(query).map_err(|e| http_error(StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+struct Routes<'r, 'a> {
+    controller: &'r Controller<'a>,
+    storage: &'r StorageService,
+    rules: &'r RuleEngine,
+    scenes: &'r SceneService,
+    manifest: &'r DeviceManifest,
+    pairing: &'r PairingService,
+    election: &'r ElectionService,
+}
+
+#[derive(Clone)]
 pub struct HttpServe {
-    listener: TcpListener,
+    listener: Rc<TcpListener>,
+    max_body: StorageEntry,
+    election: ElectionService,
 }
 
 impl HttpServe {
-    pub fn new(_: &WifiService) -> Result<Self> {
+    pub fn new(
+        _: &WifiService,
+        storage: &StorageService,
+        election: &ElectionService,
+    ) -> Result<Self> {
         let listener = std::net::TcpListener::bind("0.0.0.0:80")?;
-        Ok(Self { listener })
+        let max_body = storage.entry("http_max_body");
+        max_body.get_or_init(|| Value::from(DEFAULT_MAX_BODY));
+        Ok(Self {
+            listener: Rc::new(listener),
+            max_body,
+            election: election.clone(),
+        })
     }
-    pub async fn run<'a>(&self, controller: &Controller<'a>, storage: &StorageService) {
-        self.listener.set_nonblocking(true).unwrap();
-        while let Ok((stream, _addr)) = try_async(|| self.listener.accept()).await {
-            stream.set_nonblocking(true).ok();
-            Self::handle_stream(stream, controller, storage).await.ok();
-        }
+
+    fn max_body(&self) -> usize {
+        let max_body = self.max_body.get().as_u64().unwrap_or(DEFAULT_MAX_BODY);
+        max_body.min(MAX_BODY_LIMIT as u64) as usize
     }
 
-    async fn handle_stream<'a>(
-        mut stream: TcpStream,
+    pub async fn run<'a>(
+        &self,
         controller: &Controller<'a>,
         storage: &StorageService,
+        rules: &RuleEngine,
+        scenes: &SceneService,
+        manifest: &DeviceManifest,
+        pairing: &PairingService,
     ) -> Result<()> {
-        let mut buf = [0u8; 1024];
-        let mut start = 0;
-        let req = loop {
-            let len = try_async(|| stream.read(&mut buf[start..])).await?;
-            start += len;
-            if let Some((req, size)) = try_parse_request(&buf[..start])? {
-                if size < start {
-                    buf.copy_within(size..start, 0);
-                    start -= size;
+        self.listener.set_nonblocking(true)?;
+        let routes = Routes {
+            controller,
+            storage,
+            rules,
+            scenes,
+            manifest,
+            pairing,
+            election: &self.election,
+        };
+        let active = Cell::new(0);
+        let buffered = Cell::new(0);
+        let ex = LocalExecutor::new();
+        let accept = async {
+            loop {
+                let (stream, addr) = try_async(|| self.listener.accept()).await?;
+                if let Err(e) = stream.set_nonblocking(true) {
+                    println!("http {addr}: {e}");
+                    continue;
                 }
-                break req;
+                let mut conn = HttpConnection {
+                    stream,
+                    buf: Vec::new(),
+                };
+                let Some(slot) = Slot::take(&active, 1, MAX_CONNECTIONS) else {
+                    println!("http {addr}: too many connections");
+                    let res = Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .header(header::RETRY_AFTER, "1")
+                        .body(Vec::new())?;
+                    ex.spawn(async move {
+                        conn.respond(res, false, true).await.ok();
+                    })
+                    .detach();
+                    continue;
+                };
+                let routes = &routes;
+                let buffered = &buffered;
+                ex.spawn(async move {
+                    let _slot = slot;
+                    if let Err(e) = self.handle_stream(&mut conn, routes, buffered).await {
+                        println!("http {addr}: {e}");
+                    }
+                })
+                .detach();
             }
         };
+        ex.run(accept).await
+    }
 
-        match (req.method().clone(), req.uri().path()) {
-            (Method::GET, "/") => {
-                let res = Response::new(include_str!("index.html"));
-                write_respond(stream, res).await?;
-            }
-            (Method::GET, "/schema") => {
-                let body = dbg!(serde_json::to_string(&controller.get_schema()))?;
-                let mut res = Response::new(&body);
-                res.headers_mut()
-                    .append("Content-Type", HeaderValue::from_str("application/json")?);
-                dbg!(write_respond(stream, res).await)?;
+    async fn handle_stream(
+        &self,
+        conn: &mut HttpConnection,
+        routes: &Routes<'_, '_>,
+        buffered: &Cell<usize>,
+    ) -> Result<()> {
+        for served in 1..=MAX_REQUESTS {
+            let req = match timeout(READ_TIMEOUT, conn.read_head()).await {
+                Ok(Some(req)) => req,
+                Ok(None) => return Ok(()),
+                Err(e) => return conn.fail(e).await,
+            };
+            let read = conn.read_body(&req, self.max_body(), buffered);
+            let (body, _slot) = match timeout(READ_TIMEOUT, read).await {
+                Ok(read) => read,
+                Err(e) => return conn.fail(e).await,
+            };
+            let close = !keep_alive(&req) || served == MAX_REQUESTS;
+            let res = match routes.handle(&req, &body).await {
+                Ok(res) => res,
+                Err(e) => match e.downcast_ref::<HttpError>() {
+                    Some(HttpError(status, message)) => error(*status, message.clone())?,
+                    None => error(StatusCode::BAD_REQUEST, e.to_string())?,
+                },
+            };
+            conn.respond(res, req.version() == Version::HTTP_11, close)
+                .await?;
+            if close {
+                break;
             }
+        }
+        Ok(())
+    }
+}
+
+impl Routes<'_, '_> {
+    async fn handle(&self, req: &Request<()>, body: &[u8]) -> Result<Response<Vec<u8>>> {
+        let Self {
+            controller,
+            storage,
+            rules,
+            scenes,
+            manifest,
+            pairing,
+            election,
+        } = self;
+        match (req.method().clone(), req.uri().path()) {
+            (Method::GET, "/") => Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "text/html")
+                .body(include_str!("index.html").as_bytes().to_vec())?),
+            // Only the coordinator serves the whole mesh, so members do not
+            // each keep a UI of things they may not reach.
+            (Method::GET, "/schema") => match election.is_coordinator() {
+                true => json(&controller.get_schema()),
+                false => json(&controller.get_local_schema()),
+            },
             (Method::GET, "/data") => {
                 #[derive(Deserialize)]
                 struct Query {
@@ -80,38 +485,155 @@ impl HttpServe {
                 struct Ret {
                     value: Value,
                 }
-                if let Some(Query { field }) =
-                    req.uri().query().map(serde_qs::from_str).transpose()?
-                {
-                    let value = storage.get(field.as_str());
-                    let ret = Ret { value };
-
-                    let body = serde_json::to_string(&ret)?;
-                    let mut res = Response::new(&body);
-                    res.headers_mut()
-                        .append("Content-Type", HeaderValue::from_str("application/json")?);
-                    write_respond(stream, res).await?;
-                }
+                let Query { field } = query(req)?;
+                let value = storage.get(field.as_str());
+                json(&Ret { value })
             }
             (Method::POST, "/data") => {
-                let len = if let Some(value) = req.headers().get("Content-Length") {
-                    let len = value.to_str()?.parse()?;
-                    if len > start {
-                        stream.read_exact(&mut buf[start..][..len - start])?;
+                let val: BTreeMap<String, Value> = serde_json::from_slice(body)?;
+                let mut errors = BTreeMap::new();
+                for (k, v) in val {
+                    if let Err(e) = controller.write(&k, v).await {
+                        errors.insert(k, e.to_string());
                     }
-                    len
+                }
+                if errors.is_empty() {
+                    Ok(empty())
                 } else {
-                    start
-                };
-                let val: BTreeMap<String, Value> = serde_json::from_slice(&buf[..len])?;
-                val.into_iter().for_each(|(k, v)| storage.set_check(&k, v));
-                let res = Response::new(b"");
-                write_respond(stream, res).await?;
+                    let mut res = json(&errors)?;
+                    *res.status_mut() = StatusCode::BAD_REQUEST;
+                    Ok(res)
+                }
+            }
+            (Method::GET, "/rules") => json(&rules.get_rules()),
+            (Method::POST, "/rules") => {
+                let val: Vec<Rule> = serde_json::from_slice(body)?;
+                match rules.set_rules(val, &controller.get_schema()) {
+                    Ok(()) => Ok(empty()),
+                    Err(e) => error(StatusCode::BAD_REQUEST, e.to_string()),
+                }
+            }
+            (Method::GET, "/scenes") => json(&scenes.get_scenes()),
+            (Method::POST, "/scenes") => {
+                #[derive(Deserialize)]
+                struct Capture {
+                    name: String,
+                    keys: Vec<String>,
+                }
+                let Capture { name, keys } = serde_json::from_slice(body)?;
+                match scenes.capture(controller, &name, &keys).await {
+                    Ok(scene) => json(&scene),
+                    Err(e) => error(StatusCode::BAD_REQUEST, e.to_string()),
+                }
+            }
+            (Method::DELETE, "/scenes") => {
+                #[derive(Deserialize)]
+                struct Query {
+                    name: String,
+                }
+                let Query { name } = query(req)?;
+                match scenes.remove(&name) {
+                    Ok(()) => Ok(empty()),
+                    Err(e) => error(StatusCode::NOT_FOUND, e.to_string()),
+                }
+            }
+            (Method::GET, "/devices") => json(&manifest.get()),
+            (Method::POST, "/devices") => {
+                let val: Value = serde_json::from_slice(body)?;
+                match manifest.set(val) {
+                    Ok(()) => Ok(empty()),
+                    Err(e) => error(StatusCode::BAD_REQUEST, e.to_string()),
+                }
             }
-            _ => (),
+            (Method::GET, "/peers") => json(&pairing.get_paired()),
+            (Method::GET, "/topics") => json(&controller.espnow().topics().get_subscriptions()),
+            (Method::POST, "/topics") => {
+                #[derive(Deserialize)]
+                struct Query {
+                    topic: String,
+                }
+                let values: BTreeMap<String, Value> = serde_json::from_slice(body)?;
+                let Query { topic } = query(req)?;
+                let mut errors = BTreeMap::new();
+                for (key, value) in values {
+                    if let Err(e) = controller.publish(&topic, &key, value).await {
+                        errors.insert(key, e.to_string());
+                    }
+                }
+                if errors.is_empty() {
+                    Ok(empty())
+                } else {
+                    let mut res = json(&errors)?;
+                    *res.status_mut() = StatusCode::BAD_REQUEST;
+                    Ok(res)
+                }
+            }
+            (Method::GET, "/outbox") => json(&controller.outbox().get()),
+            (Method::DELETE, "/outbox") => {
+                #[derive(Deserialize)]
+                struct Query {
+                    peer: String,
+                }
+                let Query { peer } = query(req)?;
+                match controller.outbox().clear(&peer) {
+                    Ok(()) => Ok(empty()),
+                    Err(e) => error(StatusCode::NOT_FOUND, e.to_string()),
+                }
+            }
+            (Method::GET, "/espnow/peers") => json(&controller.espnow().peers()),
+            (Method::DELETE, "/espnow/peers") => {
+                #[derive(Deserialize)]
+                struct Query {
+                    addr: String,
+                }
+                let Query { addr } = query(req)?;
+                let addr = addr
+                    .from_base58()
+                    .ok()
+                    .and_then(|addr| <[u8; 6]>::try_from(addr).ok())
+                    .ok_or_else(|| http_error(StatusCode::BAD_REQUEST, "invalid addr"))?;
+                match controller.espnow().remove_peer(addr) {
+                    Ok(()) => Ok(empty()),
+                    Err(e) => error(StatusCode::NOT_FOUND, e.to_string()),
+                }
+            }
+            (Method::DELETE, "/peers") => {
+                #[derive(Deserialize)]
+                struct Query {
+                    id: String,
+                }
+                let Query { id } = query(req)?;
+                match pairing.remove(&id) {
+                    Ok(()) => Ok(empty()),
+                    Err(e) => error(StatusCode::NOT_FOUND, e.to_string()),
+                }
+            }
+            _ => error(StatusCode::NOT_FOUND, "not found"),
         }
+    }
+}
 
-        Ok(())
+impl Schema for HttpServe {
+    fn get_schema(&self) -> DataSchema {
+        let max_body = DataSchema {
+            id: self.max_body.get_key().to_string(),
+            title: Some(String::from("Largest request body")),
+            unit: Some(String::from("bytes")),
+            local_only: true,
+            detail: DetailDataSchema::Integer {
+                minimum: Some(READ_CHUNK as i64),
+                maximum: Some(MAX_BODY_LIMIT),
+            },
+            ..Default::default()
+        };
+        let mut map = BTreeMap::new();
+        map.insert(max_body.id.clone(), max_body);
+        DataSchema {
+            id: String::from("http"),
+            title: Some(String::from("HTTP server")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
     }
 }
 //pub struct HttpServe {
@@ -228,38 +750,73 @@ impl HttpServe {
 //    stream.write_all(response.body().as_ref())?;
 //    Ok(())
 //}
-async fn write_respond<T: AsRef<[u8]>>(
-    mut stream: impl Write,
-    response: Response<T>,
+async fn write_all(stream: &mut TcpStream, mut data: &[u8]) -> Result<()> {
+    // `write_all` would restart from the top after a WouldBlock and send
+    // the start twice, so partial writes are tracked here.
+    while !data.is_empty() {
+        let len = try_async(|| stream.write(data)).await?;
+        if len == 0 {
+            bail!("connection closed");
+        }
+        data = &data[len..];
+    }
+    Ok(())
+}
+// Long bodies go out chunked to clients that understand it, a chunk per
+// write; everything else gets a Content-Length.
+async fn write_respond(
+    stream: &mut TcpStream,
+    mut response: Response<Vec<u8>>,
+    chunked: bool,
+    close: bool,
 ) -> Result<()> {
-    try_async(|| stream.write_all(b"HTTP/1.1 ")).await?;
-    try_async(|| stream.write_all(response.status().as_str().as_bytes())).await?;
-    try_async(|| stream.write_all(b" ")).await?;
-    try_async(|| {
-        stream.write_all(
-            response
-                .status()
-                .canonical_reason()
-                .unwrap_or("")
-                .as_bytes(),
-        )
-    })
-    .await?;
-    try_async(|| stream.write_all(b"\r\n")).await?;
+    let len = response.body().len();
+    let chunked = chunked && len > CHUNK_SIZE;
+    let headers = response.headers_mut();
+    match chunked {
+        true => headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        ),
+        false => headers.insert(header::CONTENT_LENGTH, len.into()),
+    };
+    let connection = match close {
+        true => "close",
+        false => "keep-alive",
+    };
+    headers.insert(header::CONNECTION, HeaderValue::from_static(connection));
+
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status().as_str(),
+        response.status().canonical_reason().unwrap_or("")
+    )
+    .into_bytes();
     for (key, value) in response.headers() {
-        try_async(|| stream.write_all(key.as_str().as_bytes())).await?;
-        try_async(|| stream.write_all(b": ")).await?;
-        try_async(|| stream.write_all(value.as_bytes())).await?;
-        try_async(|| stream.write_all(b"\r\n")).await?;
+        head.extend_from_slice(key.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
     }
-    try_async(|| stream.write_all(b"\r\n")).await?;
-    try_async(|| stream.write_all(response.body().as_ref())).await?;
-    Ok(())
+    head.extend_from_slice(b"\r\n");
+    write_all(stream, &head).await?;
+    if !chunked {
+        return write_all(stream, response.body()).await;
+    }
+    for chunk in response.body().chunks(CHUNK_SIZE) {
+        write_all(stream, format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+        write_all(stream, chunk).await?;
+        write_all(stream, b"\r\n").await?;
+    }
+    write_all(stream, b"0\r\n\r\n").await
 }
 fn try_parse_request(buf: &[u8]) -> Result<Option<(http::Request<()>, usize)>> {
-    let mut headers = [httparse::EMPTY_HEADER; 15];
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    match req.parse(buf)? {
+    let status = req
+        .parse(buf)
+        .map_err(|e| http_error(StatusCode::BAD_REQUEST, e.to_string()))?;
+    match status {
         Status::Partial => Ok(None),
         Status::Complete(len) => {
             let mut headers = http::HeaderMap::new();
@@ -273,7 +830,10 @@ fn try_parse_request(buf: &[u8]) -> Result<Option<(http::Request<()>, usize)>> {
             *request.method_mut() = http::Method::from_bytes(req.method.unwrap_or("").as_bytes())?;
             *request.headers_mut() = headers;
             *request.uri_mut() = req.path.unwrap().parse()?;
-            *request.version_mut() = http::Version::HTTP_11;
+            *request.version_mut() = match req.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            };
 
             Ok(Some((request, len)))
         }
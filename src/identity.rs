@@ -0,0 +1,214 @@
+use crate::storage::Nvs;
+use anyhow::{anyhow, Result};
+use base58::ToBase58;
+use blake2::{digest::Mac, Blake2s256, Blake2sMac256, Digest};
+use std::{cell::RefCell, rc::Rc};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// Long enough to be collision free across a home network, short enough to
+// fit an AP SSID once base58 encoded.
+const ID_LEN: usize = 12;
+const MAX_NETWORK_KEY_LEN: usize = 64;
+// Makes each guess at a group passphrase cost this many keyed hashes.
+const GROUP_KEY_ROUNDS: u32 = 10_000;
+
+pub fn thing_id(public_key: &[u8]) -> Vec<u8> {
+    public_key[..ID_LEN.min(public_key.len())].to_vec()
+}
+
+fn derive_key<const N: usize>(label: &str, material: &[u8]) -> [u8; N] {
+    let digest = Blake2s256::new()
+        .chain_update(label.as_bytes())
+        .chain_update(material)
+        .finalize();
+    let mut key = [0u8; N];
+    key.copy_from_slice(&digest[..N]);
+    key
+}
+
+// PBKDF2 with keyed BLAKE2s in place of HMAC. Every subscriber must reach
+// the same key, so the salt is the topic rather than anything random.
+fn stretch(passphrase: &str, topic: &str) -> [u8; 32] {
+    let key: [u8; 32] = derive_key("liot group passphrase", passphrase.as_bytes());
+    let prf = <Blake2sMac256 as Mac>::new_from_slice(&key).expect("32 byte key");
+    let mut block = prf
+        .clone()
+        .chain_update(b"liot group salt")
+        .chain_update(topic.as_bytes())
+        .chain_update(1u32.to_be_bytes())
+        .finalize()
+        .into_bytes();
+    let mut out = block;
+    for _ in 1..GROUP_KEY_ROUNDS {
+        block = prf.clone().chain_update(block).finalize().into_bytes();
+        out.iter_mut().zip(block).for_each(|(o, b)| *o ^= b);
+    }
+    out.into()
+}
+
+// NVS names are limited to 15 characters, topics are not.
+fn group_key_slot(topic: &str) -> String {
+    let slot: [u8; 8] = derive_key("liot group slot", topic.as_bytes());
+    format!("g{}", slot.to_base58())
+}
+
+#[cfg(target_os = "espidf")]
+extern "C" {
+    fn bootloader_random_enable();
+    fn bootloader_random_disable();
+}
+
+// The key is made before Wi-Fi starts, when the RNG has no RF noise to draw
+// on and is only pseudo random; the bootloader's entropy source stands in
+// for the radio until it is switched off again.
+#[cfg(target_os = "espidf")]
+fn fill_random(bytes: &mut [u8]) {
+    unsafe {
+        bootloader_random_enable();
+        esp_idf_sys::esp_fill_random(bytes.as_mut_ptr().cast(), bytes.len());
+        bootloader_random_disable();
+    }
+}
+
+// The private key lives in its own NVS namespace rather than the JSON
+// property map, so it is never exposed through the schema or /data.
+#[derive(Clone)]
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+    storage: Rc<RefCell<dyn Nvs>>,
+}
+
+impl Identity {
+    #[cfg(target_os = "espidf")]
+    pub fn load_or_generate(nvs: impl Nvs + 'static) -> Result<Self> {
+        Self::load_or_insert(nvs, || {
+            let mut bytes = [0u8; 32];
+            fill_random(&mut bytes);
+            bytes
+        })
+    }
+
+    // The key from `generate` is only used, and stored, when there is none
+    // yet.
+    pub fn load_or_insert(
+        mut storage: impl Nvs + 'static,
+        generate: impl FnOnce() -> [u8; 32],
+    ) -> Result<Self> {
+        let mut buf = [0u8; 32];
+        let stored = storage
+            .get_raw("private_key", &mut buf)?
+            .map(<[u8; 32]>::try_from)
+            .transpose()
+            .map_err(|_| anyhow!("stored identity key is corrupt"))?;
+        let bytes = match stored {
+            Some(bytes) => bytes,
+            None => {
+                let bytes = generate();
+                storage.set_raw("private_key", &bytes)?;
+                println!("generated new identity key");
+                bytes
+            }
+        };
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret);
+        Ok(Self {
+            secret,
+            public,
+            storage: Rc::new(RefCell::new(storage)),
+        })
+    }
+
+    pub fn private_key(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        self.public.as_bytes()
+    }
+
+    pub fn id(&self) -> Vec<u8> {
+        thing_id(self.public_key())
+    }
+
+    pub fn name(&self) -> String {
+        self.id().to_base58()
+    }
+
+    pub fn set_network_key(&self, key: &[u8]) -> Result<()> {
+        if key.is_empty() || key.len() > MAX_NETWORK_KEY_LEN {
+            return Err(anyhow!(
+                "network key must be 1 to {MAX_NETWORK_KEY_LEN} bytes"
+            ));
+        }
+        self.storage.borrow_mut().set_raw("network_key", key)?;
+        Ok(())
+    }
+
+    // Shared by every node of one installation; without it the driver keeps
+    // its built-in PMK.
+    pub fn pmk(&self) -> Result<Option<[u8; 16]>> {
+        self.network_derived("liot espnow pmk")
+    }
+
+    // Keys tags any node of the installation can check, whoever it has
+    // paired with.
+    pub fn network_auth_key(&self) -> Result<Option<[u8; 32]>> {
+        self.network_derived("liot espnow network auth")
+    }
+
+    fn network_derived<const N: usize>(&self, label: &str) -> Result<Option<[u8; N]>> {
+        let mut buf = [0u8; MAX_NETWORK_KEY_LEN];
+        let storage = self.storage.borrow();
+        let key = storage.get_raw("network_key", &mut buf)?;
+        Ok(key.map(|key| derive_key(label, key)))
+    }
+
+    // Both ends reach the same key from their own secret and the other's
+    // pinned public key, so nothing secret crosses the air.
+    pub fn link_key(&self, peer_public: &[u8]) -> Result<[u8; 16]> {
+        Ok(derive_key(
+            "liot espnow lmk",
+            &self.shared_secret(peer_public)?,
+        ))
+    }
+
+    // Keys tags between this thing and one paired peer, apart from the LMK
+    // taken from the same exchange.
+    pub fn auth_key(&self, peer_public: &[u8]) -> Result<[u8; 32]> {
+        Ok(derive_key(
+            "liot espnow auth",
+            &self.shared_secret(peer_public)?,
+        ))
+    }
+
+    fn shared_secret(&self, peer_public: &[u8]) -> Result<[u8; 32]> {
+        let peer_public: [u8; 32] = peer_public
+            .try_into()
+            .map_err(|_| anyhow!("peer public key has the wrong length"))?;
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer_public));
+        Ok(*shared.as_bytes())
+    }
+
+    pub fn set_group_key(&self, topic: &str, passphrase: Option<&str>) -> Result<()> {
+        let slot = group_key_slot(topic);
+        let mut storage = self.storage.borrow_mut();
+        match passphrase {
+            Some(passphrase) => {
+                let key = stretch(passphrase, topic);
+                storage.set_raw(&slot, &key)?;
+            }
+            None => {
+                storage.remove(&slot)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn group_key(&self, topic: &str) -> Result<Option<[u8; 32]>> {
+        let mut buf = [0u8; 32];
+        let storage = self.storage.borrow();
+        let key = storage.get_raw(&group_key_slot(topic), &mut buf)?;
+        Ok(key.and_then(|key| key.try_into().ok()))
+    }
+}
@@ -1,71 +1,170 @@
+// Off the device only the mesh, its security and storage build, for the
+// simulated tests; what they leave unused there is used on the device.
+#![cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+
+#[cfg(target_os = "espidf")]
+pub mod clock;
+pub mod connection;
+#[cfg(target_os = "espidf")]
 pub mod controller;
 pub mod data_schema;
+#[cfg(target_os = "espidf")]
 pub mod device;
+#[cfg(target_os = "espidf")]
+pub mod election;
 pub mod espnow;
+#[cfg(target_os = "espidf")]
 pub mod http_service;
+pub mod identity;
+pub mod noise;
+pub mod outbox;
+pub mod pairing;
+#[cfg(target_os = "espidf")]
+pub mod rules;
+#[cfg(target_os = "espidf")]
+pub mod scenes;
+#[cfg(target_os = "espidf")]
+pub mod scheduler;
 pub mod storage;
+#[cfg(target_os = "espidf")]
+pub mod supervisor;
+pub mod topics;
 pub mod utils;
+#[cfg(target_os = "espidf")]
 pub mod wifi;
 
+#[cfg(target_os = "espidf")]
 use crate::utils::run_ex;
+#[cfg(target_os = "espidf")]
+use anyhow::anyhow;
+#[cfg(target_os = "espidf")]
 use async_executor::LocalExecutor;
-use base58::ToBase58;
+#[cfg(target_os = "espidf")]
 use controller::Controller;
-use device::pwm_device::PWMDevice;
+#[cfg(target_os = "espidf")]
+use data_schema::Schema;
+#[cfg(target_os = "espidf")]
+use device::factory::{DeviceFactory, DeviceManifest};
+#[cfg(target_os = "espidf")]
 use esp_idf_hal::peripherals::Peripherals;
-use espnow::{get_mac, EspNowService};
-use std::time::Duration;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+#[cfg(target_os = "espidf")]
+use espnow::{EspNowRadio, EspNowService};
+#[cfg(target_os = "espidf")]
+use identity::Identity;
+#[cfg(target_os = "espidf")]
+use pairing::PairingService;
+#[cfg(target_os = "espidf")]
+use std::{rc::Rc, time::Duration};
+#[cfg(target_os = "espidf")]
+use supervisor::Supervisor;
+#[cfg(target_os = "espidf")]
+use topics::TopicService;
 
+#[cfg(target_os = "espidf")]
 pub fn run() -> anyhow::Result<()> {
     esp_idf_sys::link_patches();
-    let p = Peripherals::take().unwrap();
-    let storage = storage::StorageService::new()?;
-    let wifi = wifi::WifiService::new(p.modem, &storage)?;
-    let http = http_service::HttpServe::new(&wifi)?;
-    let espnow = EspNowService::new(&wifi)?;
+    let p = Peripherals::take().ok_or_else(|| anyhow!("peripherals already taken"))?;
+    let nvs = EspDefaultNvsPartition::take()?;
+    let storage = storage::StorageService::new(EspNvs::new(nvs.clone(), "storage", true)?)?;
+    let identity = Identity::load_or_generate(EspNvs::new(nvs, "identity", true)?)?;
+    let wifi = wifi::WifiService::new(p.modem, &storage, &identity.name())?;
+    let pairing = PairingService::new(&storage);
+    let topics = TopicService::new(&storage, &identity);
+    let espnow = EspNowService::new(
+        Rc::new(EspNowRadio::new()?),
+        &wifi,
+        &storage,
+        &identity,
+        &pairing,
+        &topics,
+    )?;
+    let election = election::ElectionService::new(&storage);
+    let http = http_service::HttpServe::new(&wifi, &storage, &election)?;
+    let clock = clock::ClockService::new(&storage)?;
+    let scheduler = scheduler::Scheduler::new(&storage, &clock);
+    let scenes = scenes::SceneService::new(&storage);
+    let supervisor = Supervisor::new(&storage);
 
-    //let device = SensorDevice::new(p.pins.gpio9);
-    let module1 = PWMDevice::new(
-        "module-1",
-        p.ledc.timer0,
-        p.ledc.channel0,
-        p.pins.gpio3,
-        storage.clone(),
-    );
-    let module2 = PWMDevice::new(
-        "module-2",
-        p.ledc.timer1,
-        p.ledc.channel1,
-        p.pins.gpio4,
-        storage.clone(),
-    );
+    let manifest = DeviceManifest::new(&storage);
+    let mut factory = DeviceFactory::new(p.ledc, &storage);
+    let devices = manifest.build_devices(&mut factory);
+    let services: Vec<Box<dyn Schema>> = vec![
+        Box::new(manifest.clone()),
+        Box::new(clock.clone()),
+        Box::new(scheduler.clone()),
+        Box::new(scenes.clone()),
+        Box::new(supervisor.clone()),
+        Box::new(pairing.clone()),
+        Box::new(topics.clone()),
+        Box::new(espnow.clone()),
+        Box::new(election.clone()),
+        Box::new(http.clone()),
+    ];
     let controller = Controller::new(
-        &get_mac().to_base58(),
+        &identity.name(),
         wifi.clone(),
         &storage,
-        vec![Box::new(module2.clone()), Box::new(module1.clone())],
+        devices,
+        services,
         espnow.clone(),
+        &identity.private_key(),
     );
 
+    let rules = rules::RuleEngine::new(&storage);
+
     let ex = LocalExecutor::new();
-    ex.spawn(wifi.run_handle()).detach();
-    ex.spawn(module1.run_handle()).detach();
-    ex.spawn(module2.run_handle()).detach();
-    ex.spawn(http.run(&controller, &storage)).detach();
-    ex.spawn(espnow.run_handle()).detach();
-    ex.spawn(storage.periodic_store(Duration::from_secs(5)))
+    ex.spawn(supervisor.supervise("wifi", || wifi.run_handle()))
+        .detach();
+    ex.spawn(supervisor.supervise("http", || {
+        http.run(&controller, &storage, &rules, &scenes, &manifest, &pairing)
+    }))
+    .detach();
+    ex.spawn(supervisor.supervise("espnow", || espnow.run_handle()))
+        .detach();
+    ex.spawn(supervisor.supervise("espnow_channel", || espnow.track_channel(&wifi)))
+        .detach();
+    ex.spawn(supervisor.supervise("election", || election.run_handle(&espnow, &wifi)))
+        .detach();
+    ex.spawn(supervisor.supervise("storage", || storage.periodic_store(Duration::from_secs(5))))
+        .detach();
+    ex.spawn(supervisor.supervise("controller", || controller.run_handle()))
+        .detach();
+    ex.spawn(supervisor.supervise("rules", || rules.run_handle(&controller)))
+        .detach();
+    ex.spawn(supervisor.supervise("clock", || clock.run_handle()))
+        .detach();
+    ex.spawn(supervisor.supervise("timesync", || clock.sync_handle(&espnow)))
+        .detach();
+    ex.spawn(supervisor.supervise("scheduler", || scheduler.run_handle(&controller)))
+        .detach();
+    ex.spawn(supervisor.supervise("scenes", || scenes.run_handle(&controller)))
+        .detach();
+    ex.spawn(supervisor.supervise("pairing", || pairing.run_handle()))
+        .detach();
+    ex.spawn(supervisor.supervise("topics", || topics.run_handle()))
         .detach();
-    ex.spawn(controller.run_handle()).detach();
     run_ex(ex);
 }
+#[cfg(target_os = "espidf")]
 pub fn main() {
     std::thread::Builder::new()
         .stack_size(40000)
         .name("task_main".to_string())
         .spawn(|| {
-            run().ok();
+            if let Err(e) = run() {
+                println!("task_main failed: {e:?}");
+                std::thread::sleep(Duration::from_secs(5));
+                unsafe { esp_idf_sys::esp_restart() }
+            }
         })
         .unwrap()
         .join()
         .unwrap();
 }
+
+#[cfg(not(target_os = "espidf"))]
+pub fn main() {
+    println!("liot runs on ESP32; on the host, run its tests with --features sim");
+}
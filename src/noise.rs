@@ -0,0 +1,301 @@
+use crate::connection::Connection;
+use crate::identity::thing_id;
+use anyhow::{anyhow, bail, Result};
+use snow::{params::NoiseParams, Builder, HandshakeState, StatelessTransportState};
+use std::cell::{Cell, RefCell};
+
+const PATTERN_XX: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const PATTERN_IK: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+
+// First byte of every frame on the wrapped connection.
+const FRAME_XX: u8 = 0;
+const FRAME_IK: u8 = 1;
+const FRAME_TRANSPORT: u8 = 2;
+
+const DH_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+// Handshakes carry no payload, so the longest message is XX's second or
+// IK's first: an ephemeral key, the encrypted static key and a tag.
+const MAX_HANDSHAKE_LEN: usize = DH_LEN + (DH_LEN + TAG_LEN) + TAG_LEN;
+const NONCE_LEN: usize = 8;
+const REPLAY_WINDOW: u64 = 64;
+
+fn params(frame: u8) -> Result<NoiseParams> {
+    let pattern = match frame {
+        FRAME_XX => PATTERN_XX,
+        FRAME_IK => PATTERN_IK,
+        _ => bail!("unknown handshake frame {frame}"),
+    };
+    Ok(pattern.parse()?)
+}
+
+// ESP-NOW drops and reorders frames, so the nonce travels with each message
+// and a sliding window rejects replays instead of relying on a counter.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, nonce: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if nonce > highest => true,
+            Some(highest) => {
+                let age = highest - nonce;
+                age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    fn accept(&mut self, nonce: u64) {
+        match self.highest {
+            Some(highest) if nonce <= highest => self.seen |= 1 << (highest - nonce),
+            Some(highest) => {
+                let shift = nonce - highest;
+                self.seen = if shift < REPLAY_WINDOW {
+                    (self.seen << shift) | 1
+                } else {
+                    1
+                };
+                self.highest = Some(nonce);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(nonce);
+            }
+        }
+    }
+}
+
+pub struct SecureConnection<C> {
+    inner: C,
+    transport: StatelessTransportState,
+    remote_static: Vec<u8>,
+    handshake_hash: Vec<u8>,
+    send_nonce: Cell<u64>,
+    replay: RefCell<ReplayWindow>,
+}
+
+impl<C: Connection> SecureConnection<C> {
+    // The side whose `is_init` is true starts the handshake. With the
+    // responder's static key already known it uses IK, otherwise XX; the
+    // responder learns which from the first frame.
+    pub async fn handshake(
+        inner: C,
+        local_private: &[u8],
+        remote_public: Option<&[u8]>,
+    ) -> Result<Self> {
+        let mut buf = [0u8; 1 + MAX_HANDSHAKE_LEN];
+        let (frame, mut state) = if inner.is_init() {
+            let frame = if remote_public.is_some() {
+                FRAME_IK
+            } else {
+                FRAME_XX
+            };
+            let builder = Builder::new(params(frame)?).local_private_key(local_private);
+            let builder = match remote_public {
+                Some(key) => builder.remote_public_key(key),
+                None => builder,
+            };
+            (frame, builder.build_initiator()?)
+        } else {
+            let msg = inner.recv().await?;
+            let (&frame, body) = msg
+                .split_first()
+                .ok_or_else(|| anyhow!("empty handshake frame"))?;
+            let mut state = Builder::new(params(frame)?)
+                .local_private_key(local_private)
+                .build_responder()?;
+            state.read_message(body, &mut buf)?;
+            (frame, state)
+        };
+        while !state.is_handshake_finished() {
+            if state.is_my_turn() {
+                buf[0] = frame;
+                let len = state.write_message(&[], &mut buf[1..])?;
+                inner.send(&buf[..1 + len]).await?;
+            } else {
+                read_handshake(&inner, frame, &mut state, &mut buf).await?;
+            }
+        }
+        let remote_static = state
+            .get_remote_static()
+            .ok_or_else(|| anyhow!("peer sent no static key"))?
+            .to_vec();
+        let handshake_hash = state.get_handshake_hash().to_vec();
+        Ok(Self {
+            inner,
+            transport: state.into_stateless_transport_mode()?,
+            remote_static,
+            handshake_hash,
+            send_nonce: Cell::new(0),
+            replay: RefCell::new(ReplayWindow::default()),
+        })
+    }
+
+    pub fn remote_static(&self) -> &[u8] {
+        &self.remote_static
+    }
+
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+
+    fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.send_nonce.get();
+        self.send_nonce.set(nonce + 1);
+        let mut out = vec![0u8; 1 + NONCE_LEN + data.len() + TAG_LEN];
+        out[0] = FRAME_TRANSPORT;
+        out[1..1 + NONCE_LEN].copy_from_slice(&nonce.to_le_bytes());
+        let len = self
+            .transport
+            .write_message(nonce, data, &mut out[1 + NONCE_LEN..])?;
+        out.truncate(1 + NONCE_LEN + len);
+        Ok(out)
+    }
+
+    fn decrypt(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        let (nonce, body) = msg.split_at(NONCE_LEN);
+        let nonce = u64::from_le_bytes(nonce.try_into()?);
+        if !self.replay.borrow().is_fresh(nonce) {
+            bail!("replayed message {nonce}");
+        }
+        let mut out = vec![0u8; body.len()];
+        let len = self.transport.read_message(nonce, body, &mut out)?;
+        self.replay.borrow_mut().accept(nonce);
+        out.truncate(len);
+        Ok(out)
+    }
+}
+
+async fn read_handshake<C: Connection>(
+    inner: &C,
+    frame: u8,
+    state: &mut HandshakeState,
+    buf: &mut [u8],
+) -> Result<()> {
+    let msg = inner.recv().await?;
+    match msg.split_first() {
+        Some((&f, body)) if f == frame => {
+            state.read_message(body, buf)?;
+            Ok(())
+        }
+        _ => bail!("unexpected frame during handshake"),
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl<C: Connection> Connection for SecureConnection<C> {
+    fn is_init(&self) -> bool {
+        self.inner.is_init()
+    }
+    // Derived from the authenticated static key, not the transport address.
+    async fn remote_id(&self) -> Vec<u8> {
+        thing_id(&self.remote_static)
+    }
+    async fn send(&self, data: &[u8]) -> Result<()> {
+        self.inner.send(&self.encrypt(data)?).await
+    }
+    async fn send_reliable(&self, data: &[u8]) -> Result<()> {
+        self.inner.send_reliable(&self.encrypt(data)?).await
+    }
+    // Frames that fail to authenticate are dropped; a new handshake from the
+    // peer means it restarted, so the session ends and the caller reconnects.
+    async fn recv(&self) -> Result<Vec<u8>> {
+        loop {
+            let msg = self.inner.recv().await?;
+            match msg.split_first() {
+                Some((&FRAME_TRANSPORT, body)) if body.len() >= NONCE_LEN + TAG_LEN => {
+                    match self.decrypt(body) {
+                        Ok(data) => break Ok(data),
+                        Err(e) => println!("dropped frame: {e}"),
+                    }
+                }
+                Some((&FRAME_XX | &FRAME_IK, _)) => bail!("peer restarted the handshake"),
+                _ => println!("dropped malformed frame"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::MemoryConnection;
+    use futures_lite::future::{block_on, zip};
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    fn public(private: &[u8; 32]) -> [u8; 32] {
+        *PublicKey::from(&StaticSecret::from(*private)).as_bytes()
+    }
+
+    fn connect(
+        remote_public: Option<&[u8]>,
+    ) -> (
+        SecureConnection<MemoryConnection>,
+        SecureConnection<MemoryConnection>,
+    ) {
+        let (a, b) = MemoryConnection::pair(b"a", b"b");
+        let (a, b) = block_on(zip(
+            SecureConnection::handshake(a, &[1; 32], remote_public),
+            SecureConnection::handshake(b, &[2; 32], None),
+        ));
+        (a.unwrap(), b.unwrap())
+    }
+
+    #[test]
+    fn handshakes_learn_each_others_static_key() {
+        for remote_public in [None, Some(&public(&[2; 32])[..])] {
+            let (a, b) = connect(remote_public);
+            assert_eq!(a.remote_static(), public(&[2; 32]));
+            assert_eq!(b.remote_static(), public(&[1; 32]));
+            assert_eq!(a.handshake_hash(), b.handshake_hash());
+        }
+    }
+
+    #[test]
+    fn ik_fails_against_the_wrong_key() {
+        let (a, b) = MemoryConnection::pair(b"a", b"b");
+        let wrong = public(&[3; 32]);
+        let (a, b) = block_on(zip(
+            SecureConnection::handshake(a, &[1; 32], Some(&wrong)),
+            SecureConnection::handshake(b, &[2; 32], None),
+        ));
+        assert!(a.is_err() && b.is_err());
+    }
+
+    #[test]
+    fn round_trips_both_ways() {
+        let (a, b) = connect(None);
+        block_on(async {
+            a.send(b"ping").await.unwrap();
+            assert_eq!(b.recv().await.unwrap(), b"ping");
+            b.send_reliable(b"pong").await.unwrap();
+            assert_eq!(a.recv().await.unwrap(), b"pong");
+        });
+    }
+
+    #[test]
+    fn drops_replays_but_not_reordered_frames() {
+        let (a, b) = connect(None);
+        let first = a.encrypt(b"first").unwrap();
+        let second = a.encrypt(b"second").unwrap();
+        assert_eq!(b.decrypt(&second[1..]).unwrap(), b"second");
+        assert_eq!(b.decrypt(&first[1..]).unwrap(), b"first");
+        assert!(b.decrypt(&first[1..]).is_err());
+        assert!(b.decrypt(&second[1..]).is_err());
+    }
+
+    #[test]
+    fn replay_window_forgets_what_it_cannot_track() {
+        let mut window = ReplayWindow::default();
+        window.accept(REPLAY_WINDOW + 10);
+        assert!(!window.is_fresh(10));
+        assert!(window.is_fresh(11));
+        window.accept(11);
+        assert!(!window.is_fresh(11));
+        assert!(window.is_fresh(REPLAY_WINDOW + 11));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::storage::{StorageEntry, StorageService};
+use crate::utils::MIN_VALID_TIME;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const MAX_PENDING: usize = 16;
+const MAX_PEERS: usize = 16;
+// The outbox is stored with every other property, in a map boot can only
+// read back up to 20 KB of, so it gets a share rather than all of it.
+const MAX_OUTBOX_LEN: usize = 4096;
+const MAX_VALUE_LEN: usize = 1024;
+const EXPIRY: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pending {
+    // Tells a write apart from a newer one to the same key queued while it
+    // was in flight.
+    #[serde(default)]
+    pub id: u32,
+    pub key: String,
+    pub value: Value,
+    pub action: bool,
+    pub queued_at: u64,
+    pub attempts: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PeerOutbox {
+    pub pending: Vec<Pending>,
+    pub delivered: u32,
+    pub expired: u32,
+    pub dropped: u32,
+    pub refused: u32,
+    pub last_error: Option<String>,
+    #[serde(default)]
+    next_id: u32,
+}
+
+impl PeerOutbox {
+    // Writes queued before the clock was set carry boot-relative stamps,
+    // which only become comparable once it is; they are stamped anew then
+    // rather than all expiring at the first sync.
+    fn expire(&mut self, now: u64) {
+        if now < MIN_VALID_TIME {
+            return;
+        }
+        let len = self.pending.len();
+        for pending in &mut self.pending {
+            if pending.queued_at < MIN_VALID_TIME {
+                pending.queued_at = now;
+            }
+        }
+        // A clock that went backwards must not expire everything at once.
+        self.pending
+            .retain(|p| now < p.queued_at || now - p.queued_at < EXPIRY.as_secs());
+        self.expired += (len - self.pending.len()) as u32;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Writes to peers that are asleep or out of range wait here, in storage so
+// they survive a reboot, until the peer connects again.
+#[derive(Clone)]
+pub struct Outbox {
+    outbox: StorageEntry,
+}
+
+impl Outbox {
+    pub fn new(storage: &StorageService) -> Self {
+        Self {
+            outbox: storage.entry("outbox"),
+        }
+    }
+
+    pub fn get(&self) -> BTreeMap<String, PeerOutbox> {
+        serde_json::from_value(self.outbox.get()).unwrap_or_default()
+    }
+
+    fn set(&self, outbox: BTreeMap<String, PeerOutbox>) {
+        if let Ok(value) = serde_json::to_value(outbox) {
+            self.outbox.set(value);
+        }
+    }
+
+    fn update<T>(&self, peer: &str, f: impl FnOnce(&mut PeerOutbox) -> T) -> T {
+        let mut outbox = self.get();
+        let entry = outbox.entry(peer.to_string()).or_default();
+        entry.expire(now());
+        let result = f(entry);
+        self.set(outbox);
+        result
+    }
+
+    // A newer write to the same key replaces the queued one.
+    pub fn push(&self, peer: &str, key: &str, value: Value, action: bool) -> Result<()> {
+        if serde_json::to_vec(&value)?.len() > MAX_VALUE_LEN {
+            bail!("{key} is too large to queue");
+        }
+        let outbox = self.get();
+        if !outbox.contains_key(peer) && outbox.len() >= MAX_PEERS {
+            bail!("outbox is full");
+        }
+        self.update(peer, |entry| {
+            entry.pending.retain(|p| p.key != key);
+            if entry.pending.len() >= MAX_PENDING {
+                entry.pending.remove(0);
+                entry.dropped += 1;
+            }
+            entry.next_id = entry.next_id.wrapping_add(1);
+            entry.pending.push(Pending {
+                id: entry.next_id,
+                key: key.to_string(),
+                value,
+                action,
+                queued_at: now(),
+                attempts: 0,
+            });
+        });
+        self.make_room();
+        Ok(())
+    }
+
+    // Drops the oldest writes, whoever they are for, until the outbox fits.
+    fn make_room(&self) {
+        let mut outbox = self.get();
+        while serde_json::to_vec(&outbox).map_or(0, |v| v.len()) > MAX_OUTBOX_LEN {
+            let oldest = outbox
+                .values_mut()
+                .filter(|entry| !entry.pending.is_empty())
+                .min_by_key(|entry| entry.pending[0].queued_at);
+            let Some(entry) = oldest else {
+                break;
+            };
+            entry.pending.remove(0);
+            entry.dropped += 1;
+        }
+        self.set(outbox);
+    }
+
+    // Retried often, so an empty outbox is not written back each time.
+    pub fn pending(&self, peer: &str) -> Vec<Pending> {
+        let queued = self
+            .get()
+            .get(peer)
+            .is_some_and(|entry| !entry.pending.is_empty());
+        match queued {
+            true => self.update(peer, |entry| entry.pending.clone()),
+            false => Vec::new(),
+        }
+    }
+
+    pub fn delivered(&self, peer: &str, id: u32) {
+        self.update(peer, |entry| {
+            entry.pending.retain(|p| p.id != id);
+            entry.delivered += 1;
+        });
+    }
+
+    pub fn refused(&self, peer: &str, id: u32, error: String) {
+        self.update(peer, |entry| {
+            entry.pending.retain(|p| p.id != id);
+            entry.refused += 1;
+            entry.last_error = Some(error);
+        });
+    }
+
+    pub fn failed(&self, peer: &str, id: u32, error: String) {
+        self.update(peer, |entry| {
+            if let Some(pending) = entry.pending.iter_mut().find(|p| p.id == id) {
+                pending.attempts += 1;
+            }
+            entry.last_error = Some(error);
+        });
+    }
+
+    pub fn clear(&self, peer: &str) -> Result<()> {
+        let mut outbox = self.get();
+        if outbox.remove(peer).is_none() {
+            bail!("nothing queued for {peer}");
+        }
+        self.set(outbox);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryNvs;
+    use serde_json::json;
+
+    fn pending(queued_at: u64) -> Pending {
+        Pending {
+            id: 1,
+            key: String::from("module-1_state"),
+            value: Value::Bool(true),
+            action: false,
+            queued_at,
+            attempts: 0,
+        }
+    }
+
+    #[test]
+    fn keeps_writes_queued_before_the_clock_was_set() {
+        let mut entry = PeerOutbox {
+            pending: vec![pending(120)],
+            ..Default::default()
+        };
+        entry.expire(300);
+        entry.expire(MIN_VALID_TIME + 3600);
+        assert_eq!(entry.pending.len(), 1);
+        assert_eq!(entry.pending[0].queued_at, MIN_VALID_TIME + 3600);
+        entry.expire(MIN_VALID_TIME + 3600 + EXPIRY.as_secs());
+        assert!(entry.pending.is_empty());
+        assert_eq!(entry.expired, 1);
+    }
+
+    #[test]
+    fn keeps_a_newer_write_when_the_older_one_is_acked() {
+        let storage = StorageService::new(MemoryNvs::default()).unwrap();
+        let outbox = Outbox::new(&storage);
+        outbox.push("peer", "level", json!(1), false).unwrap();
+        let in_flight = outbox.pending("peer").remove(0);
+        outbox.push("peer", "level", json!(2), false).unwrap();
+        outbox.delivered("peer", in_flight.id);
+        let queued = outbox.pending("peer");
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].value, json!(2));
+    }
+}
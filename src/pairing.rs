@@ -0,0 +1,248 @@
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::storage::{StorageEntry, StorageService};
+use anyhow::{anyhow, bail, Result};
+use base58::{FromBase58, ToBase58};
+use futures_lite::future::or;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc, time::Duration};
+
+const PAIRING_WINDOW: Duration = Duration::from_secs(120);
+const MAX_PAIRED: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PairedPeer {
+    pub id: String,
+    pub public_key: String,
+}
+
+struct Candidate {
+    id: String,
+    public_key: Vec<u8>,
+    code: String,
+}
+
+pub enum Trust {
+    Paired,
+    Mismatch,
+    Unknown,
+}
+
+// Both ends derive the code from the same Noise handshake hash, so matching
+// codes on the two devices prove there is no one in the middle.
+pub fn pairing_code(handshake_hash: &[u8]) -> String {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&handshake_hash[..4]);
+    format!("{:06}", u32::from_le_bytes(bytes) % 1_000_000)
+}
+
+#[derive(Clone)]
+pub struct PairingService {
+    paired: StorageEntry,
+    window: StorageEntry,
+    confirm: StorageEntry,
+    pending: StorageEntry,
+    candidates: Rc<RefCell<Vec<Candidate>>>,
+}
+
+impl PairingService {
+    pub fn new(storage: &StorageService) -> Self {
+        let this = Self {
+            paired: storage.entry("paired_peers"),
+            window: storage.entry("pairing_window"),
+            confirm: storage.entry("pairing_confirm"),
+            pending: storage.entry("pairing_pending"),
+            candidates: Rc::new(RefCell::new(Vec::new())),
+        };
+        this.window.set(Value::Bool(false));
+        this.update_pending();
+        this
+    }
+
+    pub fn get_paired(&self) -> Vec<PairedPeer> {
+        serde_json::from_value(self.paired.get_or_init(|| Value::Array(Vec::new())))
+            .unwrap_or_default()
+    }
+
+    fn set_paired(&self, paired: Vec<PairedPeer>) -> Result<()> {
+        self.paired.set(serde_json::to_value(paired)?);
+        Ok(())
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.get().as_bool() == Some(true)
+    }
+
+    pub fn is_paired(&self, id: &str) -> bool {
+        self.get_paired().iter().any(|p| p.id == id)
+    }
+
+    pub fn public_key(&self, id: &str) -> Option<Vec<u8>> {
+        self.get_paired()
+            .into_iter()
+            .find(|p| p.id == id)
+            .and_then(|p| p.public_key.from_base58().ok())
+    }
+
+    // Whether frames claiming this id may reach the handshake at all.
+    pub fn admits(&self, id: &[u8]) -> bool {
+        self.is_open() || self.is_paired(&id.to_base58())
+    }
+
+    pub fn check(&self, id: &str, public_key: &[u8]) -> Trust {
+        match self.get_paired().into_iter().find(|p| p.id == id) {
+            Some(p) if p.public_key == public_key.to_base58() => Trust::Paired,
+            Some(_) => Trust::Mismatch,
+            None => Trust::Unknown,
+        }
+    }
+
+    pub fn offer(&self, id: &str, public_key: &[u8], handshake_hash: &[u8]) -> Option<String> {
+        if !self.is_open() {
+            return None;
+        }
+        let code = pairing_code(handshake_hash);
+        let mut candidates = self.candidates.borrow_mut();
+        candidates.retain(|c| c.id != id);
+        candidates.push(Candidate {
+            id: id.to_string(),
+            public_key: public_key.to_vec(),
+            code: code.clone(),
+        });
+        drop(candidates);
+        self.update_pending();
+        Some(code)
+    }
+
+    // Resolves whenever the list of paired peers changes.
+    pub async fn wait_changed(&self) {
+        self.paired.wait_new().await;
+    }
+
+    // Resolves true once the user confirms this peer, false when the window
+    // closes without that happening.
+    pub async fn wait_decision(&self, id: &str) -> bool {
+        loop {
+            if self.is_paired(id) {
+                return true;
+            }
+            if !self.is_open() {
+                return false;
+            }
+            or(self.paired.wait_new(), self.window.wait_new()).await;
+        }
+    }
+
+    pub fn confirm(&self, code_or_id: &str) -> Result<PairedPeer> {
+        let candidate = {
+            let mut candidates = self.candidates.borrow_mut();
+            let index = candidates
+                .iter()
+                .position(|c| c.code == code_or_id || c.id == code_or_id)
+                .ok_or_else(|| anyhow!("no pending peer matches {code_or_id}"))?;
+            candidates.remove(index)
+        };
+        self.update_pending();
+        let mut paired = self.get_paired();
+        paired.retain(|p| p.id != candidate.id);
+        if paired.len() >= MAX_PAIRED {
+            bail!("too many paired peers");
+        }
+        let peer = PairedPeer {
+            id: candidate.id,
+            public_key: candidate.public_key.to_base58(),
+        };
+        paired.push(peer.clone());
+        self.set_paired(paired)?;
+        Ok(peer)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<()> {
+        let mut paired = self.get_paired();
+        let len = paired.len();
+        paired.retain(|p| p.id != id);
+        if paired.len() == len {
+            bail!("unknown peer {id}");
+        }
+        self.set_paired(paired)
+    }
+
+    fn update_pending(&self) {
+        let pending = self
+            .candidates
+            .borrow()
+            .iter()
+            .map(|c| format!("{} (code {})", c.id, c.code))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.pending.set(Value::String(pending));
+    }
+
+    pub async fn run_handle(&self) {
+        let window = async {
+            loop {
+                if self.window.wait_new().await.as_bool() != Some(true) {
+                    continue;
+                }
+                println!("pairing window open");
+                let closed =
+                    async { while self.window.wait_new().await.as_bool() == Some(true) {} };
+                or(futures_timer::Delay::new(PAIRING_WINDOW), closed).await;
+                if self.is_open() {
+                    self.window.set(Value::Bool(false));
+                }
+                self.candidates.borrow_mut().clear();
+                self.update_pending();
+                println!("pairing window closed");
+            }
+        };
+        let confirm = async {
+            loop {
+                if let Some(code) = self.confirm.wait_new().await.as_str() {
+                    match self.confirm(code) {
+                        Ok(peer) => println!("paired with {}", peer.id),
+                        Err(e) => println!("{e}"),
+                    }
+                }
+            }
+        };
+        or(window, confirm).await
+    }
+}
+
+impl Schema for PairingService {
+    fn get_schema(&self) -> DataSchema {
+        let window = DataSchema {
+            id: self.window.get_key().to_string(),
+            title: Some(String::from("Pairing window")),
+            local_only: true,
+            detail: DetailDataSchema::Bool,
+            ..Default::default()
+        };
+        let pending = DataSchema {
+            id: self.pending.get_key().to_string(),
+            title: Some(String::from("Waiting to pair")),
+            read_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let confirm = DataSchema {
+            id: self.confirm.get_key().to_string(),
+            title: Some(String::from("Confirm code or id")),
+            write_only: true,
+            local_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let mut map = BTreeMap::new();
+        map.insert(window.id.clone(), window);
+        map.insert(pending.id.clone(), pending);
+        map.insert(confirm.id.clone(), confirm);
+        DataSchema {
+            id: String::from("pairing"),
+            title: Some(String::from("Pairing")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use crate::controller::Controller;
+use crate::data_schema::{DetailDataSchema, ThingSchema};
+use crate::storage::{StorageEntry, StorageService};
+use anyhow::{anyhow, bail, Result};
+use async_executor::LocalExecutor;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+const MAX_INVOKE_DEPTH: usize = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub id: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub triggers: Vec<Trigger>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<Action>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Trigger {
+    Change { key: String },
+    Above { key: String, threshold: f64 },
+    Below { key: String, threshold: f64 },
+    Schedule { every: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Condition {
+    pub key: String,
+    pub op: Op,
+    pub value: Value,
+}
+
+// `invoke` runs the actions of another rule, `invoke_action` runs a
+// write-only action of this thing or of a peer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    Set { key: String, value: Value },
+    Toggle { key: String },
+    Delay { secs: u64 },
+    Invoke { rule: String },
+    InvokeAction { action: String, input: Value },
+}
+
+impl Condition {
+    pub fn check(&self, value: &Value) -> bool {
+        match self.op {
+            Op::Eq => *value == self.value,
+            Op::Ne => *value != self.value,
+            op => match (value.as_f64(), self.value.as_f64()) {
+                (Some(a), Some(b)) => match op {
+                    Op::Lt => a < b,
+                    Op::Le => a <= b,
+                    Op::Gt => a > b,
+                    _ => a >= b,
+                },
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Rule {
+    pub fn validate(&self, schema: &ThingSchema, rules: &[Rule]) -> Result<()> {
+        let field = |key: &str| {
+            schema
+                .find(key)
+                .ok_or_else(|| anyhow!("rule {}: unknown key {key}", self.id))
+        };
+        if self.triggers.is_empty() {
+            bail!("rule {} has no trigger", self.id);
+        }
+        for trigger in &self.triggers {
+            match trigger {
+                Trigger::Change { key } => {
+                    field(key)?;
+                }
+                Trigger::Above { key, .. } | Trigger::Below { key, .. } => {
+                    if !matches!(
+                        field(key)?.detail,
+                        DetailDataSchema::Number { .. } | DetailDataSchema::Integer { .. }
+                    ) {
+                        bail!("rule {}: {key} is not a number", self.id);
+                    }
+                }
+                Trigger::Schedule { every } => {
+                    if *every == 0 {
+                        bail!("rule {}: schedule interval must not be 0", self.id);
+                    }
+                }
+            }
+        }
+        for condition in &self.conditions {
+            field(&condition.key)?;
+        }
+        for action in &self.actions {
+            match action {
+                Action::Set { key, value } => field(key)?.validate(value)?,
+                Action::Toggle { key } => {
+                    if !matches!(field(key)?.detail, DetailDataSchema::Bool) {
+                        bail!("rule {}: {key} is not a bool", self.id);
+                    }
+                }
+                Action::Delay { .. } => (),
+                Action::Invoke { rule } => {
+                    if !rules.iter().any(|r| &r.id == rule) {
+                        bail!("rule {}: unknown rule {rule}", self.id);
+                    }
+                }
+                Action::InvokeAction { action, input } => {
+                    let field = field(action)?;
+                    if !field.write_only {
+                        bail!("rule {}: {action} is not an action", self.id);
+                    }
+                    field.validate(input)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct RuleEngine {
+    storage: StorageService,
+    rules: StorageEntry,
+}
+
+impl RuleEngine {
+    pub fn new(storage: &StorageService) -> Self {
+        Self {
+            storage: storage.clone(),
+            rules: storage.entry("rules"),
+        }
+    }
+
+    pub fn get_rules(&self) -> Vec<Rule> {
+        serde_json::from_value(self.rules.get_or_init(|| Value::Array(Vec::new())))
+            .unwrap_or_default()
+    }
+
+    pub fn set_rules(&self, rules: Vec<Rule>, schema: &ThingSchema) -> Result<()> {
+        for (i, rule) in rules.iter().enumerate() {
+            if rules[..i].iter().any(|r| r.id == rule.id) {
+                bail!("duplicate rule {}", rule.id);
+            }
+            rule.validate(schema, &rules)?;
+        }
+        self.rules.set(serde_json::to_value(rules)?);
+        Ok(())
+    }
+
+    pub async fn run_handle(&self, controller: &Controller<'_>) {
+        loop {
+            let rules = self.get_rules();
+            let ex = LocalExecutor::new();
+            for rule in rules.iter().filter(|r| r.enabled) {
+                for trigger in &rule.triggers {
+                    ex.spawn(self.watch(controller, &rules, rule, trigger))
+                        .detach();
+                }
+            }
+            ex.run(self.rules.wait_new()).await;
+        }
+    }
+
+    async fn watch(
+        &self,
+        controller: &Controller<'_>,
+        rules: &[Rule],
+        rule: &Rule,
+        trigger: &Trigger,
+    ) {
+        let mut last = match trigger {
+            Trigger::Above { key, .. } | Trigger::Below { key, .. } => {
+                self.storage.get(key).as_f64()
+            }
+            _ => None,
+        };
+        loop {
+            match trigger {
+                Trigger::Change { key } => {
+                    self.storage.wait_new(key).await;
+                }
+                Trigger::Above { key, threshold } | Trigger::Below { key, threshold } => {
+                    let new = self.storage.wait_new(key).await.as_f64();
+                    let crossed = match (last, new) {
+                        (Some(old), Some(new)) => match trigger {
+                            Trigger::Above { .. } => old <= *threshold && new > *threshold,
+                            _ => old >= *threshold && new < *threshold,
+                        },
+                        _ => false,
+                    };
+                    last = new;
+                    if !crossed {
+                        continue;
+                    }
+                }
+                Trigger::Schedule { every } => {
+                    futures_timer::Delay::new(Duration::from_secs(*every)).await;
+                }
+            }
+            if rule
+                .conditions
+                .iter()
+                .all(|c| c.check(&self.storage.get(&c.key)))
+            {
+                if let Err(e) = self.execute(controller, rules, rule).await {
+                    println!("rule {} failed: {e}", rule.id);
+                }
+            }
+        }
+    }
+
+    async fn execute(
+        &self,
+        controller: &Controller<'_>,
+        rules: &[Rule],
+        rule: &Rule,
+    ) -> Result<()> {
+        let mut actions = Vec::new();
+        expand(rules, &rule.actions, 0, &mut actions);
+        for action in actions {
+            match action {
+                Action::Set { key, value } => controller.write(key, value.clone()).await?,
+                Action::Toggle { key } => {
+                    let value = !self.storage.get(key).as_bool().unwrap_or(false);
+                    controller.write(key, Value::Bool(value)).await?
+                }
+                Action::Delay { secs } => {
+                    futures_timer::Delay::new(Duration::from_secs(*secs)).await
+                }
+                Action::InvokeAction { action, input } => {
+                    controller.write(action, input.clone()).await?
+                }
+                Action::Invoke { .. } => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn expand<'r>(rules: &'r [Rule], actions: &'r [Action], depth: usize, out: &mut Vec<&'r Action>) {
+    for action in actions {
+        match action {
+            Action::Invoke { rule } if depth < MAX_INVOKE_DEPTH => {
+                if let Some(rule) = rules.iter().find(|r| &r.id == rule) {
+                    expand(rules, &rule.actions, depth + 1, out);
+                }
+            }
+            action => out.push(action),
+        }
+    }
+}
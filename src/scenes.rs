@@ -0,0 +1,141 @@
+use crate::controller::Controller;
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::storage::{StorageEntry, StorageService};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+const MAX_SCENES: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Scene {
+    pub name: String,
+    pub values: Vec<(String, Value)>,
+}
+
+#[derive(Clone)]
+pub struct SceneService {
+    scenes: StorageEntry,
+    recall: StorageEntry,
+}
+
+impl SceneService {
+    pub fn new(storage: &StorageService) -> Self {
+        Self {
+            scenes: storage.entry("scenes"),
+            recall: storage.entry("scene_recall"),
+        }
+    }
+
+    pub fn get_scenes(&self) -> Vec<Scene> {
+        serde_json::from_value(self.scenes.get_or_init(|| Value::Array(Vec::new())))
+            .unwrap_or_default()
+    }
+
+    fn set_scenes(&self, scenes: Vec<Scene>) -> Result<()> {
+        self.scenes.set(serde_json::to_value(scenes)?);
+        Ok(())
+    }
+
+    pub async fn capture(
+        &self,
+        controller: &Controller<'_>,
+        name: &str,
+        keys: &[String],
+    ) -> Result<Scene> {
+        if name.is_empty() {
+            bail!("scene name must not be empty");
+        }
+        let mut values = Vec::new();
+        for key in keys {
+            let value = controller.read(key).await?;
+            if value.is_null() {
+                bail!("{key} has no value");
+            }
+            values.push((key.clone(), value));
+        }
+        let scene = Scene {
+            name: name.to_string(),
+            values,
+        };
+        let mut scenes = self.get_scenes();
+        scenes.retain(|s| s.name != name);
+        if scenes.len() >= MAX_SCENES {
+            bail!("too many scenes");
+        }
+        scenes.push(scene.clone());
+        self.set_scenes(scenes)?;
+        Ok(scene)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let mut scenes = self.get_scenes();
+        let len = scenes.len();
+        scenes.retain(|s| s.name != name);
+        if scenes.len() == len {
+            bail!("unknown scene {name}");
+        }
+        self.set_scenes(scenes)
+    }
+
+    // Values are written in capture order and the executor gets a turn after
+    // each one, so a `_state` key lands before the `_duty` that follows it and
+    // PWM duties still go through the device's soft fade.
+    pub async fn recall(&self, controller: &Controller<'_>, name: &str) -> Result<()> {
+        let scene = self
+            .get_scenes()
+            .into_iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| anyhow!("unknown scene {name}"))?;
+        for (key, value) in scene.values {
+            if let Err(e) = controller.write(&key, value).await {
+                println!("scene {name} failed to set {key}: {e}");
+            }
+            futures_lite::future::yield_now().await;
+        }
+        Ok(())
+    }
+
+    pub async fn run_handle(&self, controller: &Controller<'_>) {
+        loop {
+            if let Some(name) = self.recall.wait_new().await.as_str() {
+                if let Err(e) = self.recall(controller, name).await {
+                    println!("{e}");
+                }
+            }
+        }
+    }
+}
+
+impl Schema for SceneService {
+    fn get_schema(&self) -> DataSchema {
+        let scenes = self
+            .get_scenes()
+            .into_iter()
+            .map(|scene| DataSchema {
+                id: scene.name.clone(),
+                title: Some(scene.name.clone()),
+                r#const: Value::String(scene.name),
+                detail: DetailDataSchema::String,
+                ..Default::default()
+            })
+            .collect();
+        let recall = DataSchema {
+            id: self.recall.get_key().to_string(),
+            title: Some(String::from("Recall scene")),
+            one_of: Some(scenes),
+            write_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let mut map = BTreeMap::new();
+        map.insert(recall.id.clone(), recall);
+        DataSchema {
+            id: String::from("scene"),
+            title: Some(String::from("Scenes")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use crate::clock::{ClockService, LocalTime};
+use crate::controller::Controller;
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::storage::{StorageEntry, StorageService};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
+
+const MAX_SCHEDULES: u32 = 16;
+const MAX_CATCH_UP_MINUTES: u64 = 24 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUp {
+    #[default]
+    Skip,
+    Last,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduleEntry {
+    pub id: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub cron: String,
+    pub values: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub catch_up: CatchUp,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cron {
+    minute: u64,
+    hour: u64,
+    day: u64,
+    month: u64,
+    weekday: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((a, b)) => (a.parse()?, b.parse()?),
+                None if step > 1 => (range.parse()?, max),
+                None => (range.parse()?, range.parse()?),
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            bail!("invalid cron field {field}");
+        }
+        for i in (start..=end).step_by(step as usize) {
+            bits |= 1 << i;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!("cron needs 5 fields: minute hour day month weekday");
+        };
+        let mut weekday_bits = parse_field(weekday, 0, 7)?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Self {
+            minute: parse_field(minute, 0, 59)?,
+            hour: parse_field(hour, 0, 23)?,
+            day: parse_field(day, 1, 31)?,
+            month: parse_field(month, 1, 12)?,
+            weekday: weekday_bits,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+}
+
+impl Cron {
+    pub fn matches(&self, t: &LocalTime) -> bool {
+        let day = self.day & (1 << t.day) != 0;
+        let weekday = self.weekday & (1 << t.weekday) != 0;
+        let day = if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        };
+        self.minute & (1 << t.minute) != 0
+            && self.hour & (1 << t.hour) != 0
+            && self.month & (1 << t.month) != 0
+            && day
+    }
+}
+
+#[derive(Clone)]
+pub struct Scheduler {
+    clock: ClockService,
+    schedules: StorageEntry,
+    last_run: StorageEntry,
+    status: StorageEntry,
+}
+
+impl Scheduler {
+    pub fn new(storage: &StorageService, clock: &ClockService) -> Self {
+        Self {
+            clock: clock.clone(),
+            schedules: storage.entry("schedules"),
+            last_run: storage.entry("schedule_last_run"),
+            status: storage.entry("schedule_status"),
+        }
+    }
+
+    fn get_entries(&self) -> Vec<(ScheduleEntry, Cron)> {
+        let value = self.schedules.get_or_init(|| Value::Array(Vec::new()));
+        let entries: Vec<Value> = serde_json::from_value(value).unwrap_or_default();
+        let mut errors = Vec::new();
+        let mut ret = Vec::new();
+        for entry in entries {
+            let parsed = serde_json::from_value::<ScheduleEntry>(entry)
+                .map_err(anyhow::Error::from)
+                .and_then(|entry| Ok((entry.cron.parse()?, entry)));
+            match parsed {
+                Ok((cron, entry)) => ret.push((entry, cron)),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        let status = if errors.is_empty() {
+            String::from("ok")
+        } else {
+            errors.join("; ")
+        };
+        if self.status.get().as_str() != Some(status.as_str()) {
+            self.status.set(Value::String(status));
+        }
+        ret
+    }
+
+    async fn apply(&self, controller: &Controller<'_>, entry: &ScheduleEntry) {
+        for (key, value) in &entry.values {
+            if let Err(e) = controller.write(key, value.clone()).await {
+                println!("schedule {} failed to set {key}: {e}", entry.id);
+            }
+        }
+    }
+
+    pub async fn run_handle(&self, controller: &Controller<'_>) {
+        loop {
+            let now = match self.clock.now() {
+                Some(now) => now,
+                None => {
+                    futures_timer::Delay::new(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let minute = now / 60;
+            let last = self.last_run.get().as_u64();
+            if last != Some(minute) {
+                let entries = self.get_entries();
+                let entries = entries.iter().filter(|(entry, _)| entry.enabled);
+                for (entry, cron) in entries {
+                    let due = cron.matches(&self.clock.local_time(minute * 60))
+                        || (entry.catch_up == CatchUp::Last && self.missed(cron, last, minute));
+                    if due {
+                        self.apply(controller, entry).await;
+                    }
+                }
+                self.last_run.set(Value::from(minute));
+            }
+            futures_timer::Delay::new(Duration::from_secs(60 - now % 60)).await;
+        }
+    }
+
+    fn missed(&self, cron: &Cron, last: Option<u64>, minute: u64) -> bool {
+        let Some(last) = last else {
+            return false;
+        };
+        let from = (last + 1).max(minute.saturating_sub(MAX_CATCH_UP_MINUTES));
+        (from..minute).any(|m| cron.matches(&self.clock.local_time(m * 60)))
+    }
+}
+
+fn entry_schema() -> DataSchema {
+    let field = |id: &str, detail| DataSchema {
+        id: String::from(id),
+        detail,
+        ..Default::default()
+    };
+    let fields = [
+        field("id", DetailDataSchema::String),
+        field("enabled", DetailDataSchema::Bool),
+        DataSchema {
+            description: Some(String::from("minute hour day month weekday")),
+            ..field("cron", DetailDataSchema::String)
+        },
+        DataSchema {
+            description: Some(String::from("Values to write, by key")),
+            ..field(
+                "values",
+                DetailDataSchema::Object {
+                    properties: BTreeMap::new(),
+                },
+            )
+        },
+        DataSchema {
+            description: Some(String::from("skip or last")),
+            ..field("catch_up", DetailDataSchema::String)
+        },
+    ];
+    DataSchema {
+        id: String::from("schedule_entry"),
+        title: Some(String::from("Schedule")),
+        detail: DetailDataSchema::Object {
+            properties: fields.into_iter().map(|f| (f.id.clone(), f)).collect(),
+        },
+        ..Default::default()
+    }
+}
+
+impl Schema for Scheduler {
+    fn get_schema(&self) -> DataSchema {
+        let schedules = DataSchema {
+            id: self.schedules.get_key().to_string(),
+            title: Some(String::from("Schedules")),
+            description: Some(String::from(
+                r#"[{"id": "evening", "cron": "30 18 * * *", "values": {"module-1_state": true}, "catch_up": "last"}]"#,
+            )),
+            format: Some(String::from("json")),
+            local_only: true,
+            detail: DetailDataSchema::Array {
+                items: vec![entry_schema()],
+                min_items: 0,
+                max_items: MAX_SCHEDULES,
+            },
+            ..Default::default()
+        };
+        let status = DataSchema {
+            id: self.status.get_key().to_string(),
+            title: Some(String::from("Schedule status")),
+            read_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let mut map = BTreeMap::new();
+        map.insert(schedules.id.clone(), schedules);
+        map.insert(status.id.clone(), status);
+        DataSchema {
+            id: String::from("schedule"),
+            title: Some(String::from("Schedule")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
+    }
+}
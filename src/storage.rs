@@ -1,23 +1,76 @@
 use std::{
     cell::RefCell,
     collections::{btree_map::Entry, BTreeMap},
-    mem::discriminant,
     rc::Rc,
     time::Duration,
 };
 
-use anyhow::Result;
-use embedded_svc::storage::RawStorage;
-use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use anyhow::{bail, Result};
+#[cfg(target_os = "espidf")]
+use embedded_svc::storage::{RawStorage, StorageBase};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
 use event_listener::Event;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+// What boot reads the stored map into; a larger blob could not be loaded.
+const MAX_DATA_LEN: usize = 20480;
+
+// One NVS namespace: named blobs in flash on the device, a map in memory
+// on the host.
+pub trait Nvs {
+    fn get_raw<'a>(&self, name: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>>;
+    fn set_raw(&mut self, name: &str, data: &[u8]) -> Result<()>;
+    fn remove(&mut self, name: &str) -> Result<()>;
+}
+
+#[cfg(target_os = "espidf")]
+impl Nvs for EspNvs<NvsDefault> {
+    fn get_raw<'a>(&self, name: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>> {
+        Ok(RawStorage::get_raw(self, name, buf)?)
+    }
+    fn set_raw(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        RawStorage::set_raw(self, name, data)?;
+        Ok(())
+    }
+    fn remove(&mut self, name: &str) -> Result<()> {
+        StorageBase::remove(self, name)?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryNvs {
+    blobs: BTreeMap<String, Vec<u8>>,
+}
+
+impl Nvs for MemoryNvs {
+    fn get_raw<'a>(&self, name: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>> {
+        let Some(blob) = self.blobs.get(name) else {
+            return Ok(None);
+        };
+        if blob.len() > buf.len() {
+            bail!("{name} does not fit in {} bytes", buf.len());
+        }
+        buf[..blob.len()].copy_from_slice(blob);
+        Ok(Some(&buf[..blob.len()]))
+    }
+    fn set_raw(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        self.blobs.insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+    fn remove(&mut self, name: &str) -> Result<()> {
+        self.blobs.remove(name);
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct StorageService {
-    default_nvs: EspDefaultNvsPartition,
-    storage: Rc<RefCell<EspNvs<NvsDefault>>>,
+    storage: Rc<RefCell<dyn Nvs>>,
     map: Rc<RefCell<BTreeMap<String, DataValue>>>,
+    changed: Rc<Event>,
 }
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
@@ -27,18 +80,15 @@ pub struct DataValue {
     readers: Vec<String>,
     #[serde(skip)]
     notify: Rc<Event>,
+    #[serde(skip)]
+    volatile: bool,
 }
 
 impl StorageService {
-    pub fn new() -> Result<Self> {
-        let default_nvs = EspDefaultNvsPartition::take()?;
-        let storage = Rc::new(RefCell::new(EspNvs::new(
-            default_nvs.clone(),
-            "storage",
-            true,
-        )?));
+    pub fn new(nvs: impl Nvs + 'static) -> Result<Self> {
+        let storage = Rc::new(RefCell::new(nvs));
 
-        let mut buf = vec![0; 20480];
+        let mut buf = vec![0; MAX_DATA_LEN];
 
         let map = match storage
             .as_ref()
@@ -52,14 +102,11 @@ impl StorageService {
         };
 
         Ok(Self {
-            default_nvs,
             storage,
             map: Rc::new(RefCell::new(map)),
+            changed: Rc::new(Event::new()),
         })
     }
-    pub fn default_nvs(&self) -> EspDefaultNvsPartition {
-        self.default_nvs.clone()
-    }
     pub fn get(&self, key: &str) -> Value {
         self.get_all(key).value
     }
@@ -77,22 +124,19 @@ impl StorageService {
             .clone()
     }
 
-    pub fn set_check(&self, key: &str, value: Value) {
-        let old_value = self.get(key);
-        if discriminant(&old_value) == discriminant(&value) {
-            self.set(key, value)
-        }
-    }
-
     pub fn set(&self, key: &str, value: Value) {
         let notify = self.set_unnotice(key, value);
         notify.notify(usize::MAX);
+        self.changed.notify(usize::MAX);
     }
 
     pub fn set_unnotice(&self, key: &str, value: Value) -> Rc<Event> {
         match self.map.borrow_mut().entry(String::from(key)) {
             Entry::Vacant(e) => {
-                let dat = DataValue::default();
+                let dat = DataValue {
+                    value,
+                    ..Default::default()
+                };
                 let notify = dat.notify.clone();
                 e.insert(dat);
                 notify
@@ -105,22 +149,52 @@ impl StorageService {
         }
     }
 
+    // Kept in memory only, e.g. values mirrored from peers, so they neither
+    // wear the flash nor grow the stored map past what boot can read.
+    pub fn set_volatile(&self, key: &str, value: Value) {
+        self.set(key, value);
+        if let Some(data) = self.map.borrow_mut().get_mut(key) {
+            data.volatile = true;
+        }
+    }
+
     pub async fn wait_new(&self, key: &str) -> Value {
         let notify = self.get_all(key).notify;
         notify.listen().await;
         self.get(key)
     }
+    // Wakes on a `set` of any key.
+    pub async fn wait_changed(&self) {
+        self.changed.listen().await;
+    }
+    pub fn keys(&self) -> Vec<String> {
+        self.map.borrow().keys().cloned().collect()
+    }
+    pub fn remove(&self, key: &str) {
+        if self.map.borrow_mut().remove(key).is_some() {
+            self.changed.notify(usize::MAX);
+        }
+    }
     pub fn entry(&self, key: &str) -> StorageEntry {
         StorageEntry {
             storage: self.clone(),
             key: key.to_string(),
         }
     }
-    pub async fn periodic_store(&self, duration: Duration) {
+    pub fn store(&self) -> Result<()> {
+        let map = self.map.borrow();
+        let stored: BTreeMap<_, _> = map.iter().filter(|(_, data)| !data.volatile).collect();
+        let vec = serde_json::to_vec(&stored)?;
+        if vec.len() > MAX_DATA_LEN {
+            bail!("stored data of {} bytes would not load again", vec.len());
+        }
+        self.storage.borrow_mut().set_raw("data", &vec)?;
+        Ok(())
+    }
+    pub async fn periodic_store(&self, duration: Duration) -> Result<()> {
         loop {
             futures_timer::Delay::new(duration).await;
-            let vec = serde_json::to_vec(&*self.map.borrow()).unwrap();
-            self.storage.borrow_mut().set_raw("data", &vec).unwrap();
+            self.store()?;
         }
     }
 }
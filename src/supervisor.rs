@@ -0,0 +1,134 @@
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::storage::StorageService;
+use anyhow::Result;
+use futures_lite::Future;
+use serde_json::Value;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const STABLE_PERIOD: Duration = Duration::from_secs(120);
+const MAX_FAILURES: u32 = 5;
+
+pub trait TaskOutput {
+    fn into_result(self) -> Result<()>;
+}
+
+impl TaskOutput for () {
+    fn into_result(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl TaskOutput for Result<()> {
+    fn into_result(self) -> Result<()> {
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Supervisor {
+    storage: StorageService,
+    tasks: Rc<RefCell<Vec<String>>>,
+}
+
+impl Supervisor {
+    pub fn new(storage: &StorageService) -> Self {
+        Self {
+            storage: storage.clone(),
+            tasks: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    pub async fn supervise<F, Fut>(&self, name: &str, task: F)
+    where
+        F: Fn() -> Fut,
+        Fut: Future,
+        Fut::Output: TaskOutput,
+    {
+        self.tasks.borrow_mut().push(name.to_string());
+        let status = self.storage.entry(&format!("task_{name}"));
+        let mut restarts = 0u32;
+        let mut failures = 0u32;
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            status.set(Value::String(format!("running, {restarts} restarts")));
+            let started = Instant::now();
+            let err = match task().await.into_result() {
+                Ok(()) => {
+                    status.set(Value::String(String::from("finished")));
+                    return;
+                }
+                Err(err) => err,
+            };
+            println!("task {name} failed: {err:?}");
+            if started.elapsed() >= STABLE_PERIOD {
+                failures = 0;
+                backoff = MIN_BACKOFF;
+            }
+            restarts += 1;
+            failures += 1;
+            if failures >= MAX_FAILURES {
+                self.reboot(&format!("task {name} failed {failures} times: {err}"));
+            }
+            status.set(Value::String(format!(
+                "restarting in {}s, {restarts} restarts: {err}",
+                backoff.as_secs()
+            )));
+            futures_timer::Delay::new(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    pub fn reboot(&self, reason: &str) -> ! {
+        println!("rebooting: {reason}");
+        self.storage
+            .set("supervisor_last_reboot", Value::String(reason.to_string()));
+        if let Err(e) = self.storage.store() {
+            println!("failed to store data before reboot: {e}");
+        }
+        unsafe { esp_idf_sys::esp_restart() }
+    }
+}
+
+impl Schema for Supervisor {
+    fn get_schema(&self) -> DataSchema {
+        let mut map = BTreeMap::new();
+        for name in self.tasks.borrow().iter() {
+            let id = format!("task_{name}");
+            let task = DataSchema {
+                id: id.clone(),
+                title: Some(name.clone()),
+                description: self.storage.get(&id).as_str().map(String::from),
+                read_only: true,
+                detail: DetailDataSchema::String,
+                ..Default::default()
+            };
+            map.insert(id, task);
+        }
+        let last_reboot = DataSchema {
+            id: String::from("supervisor_last_reboot"),
+            title: Some(String::from("Last reboot reason")),
+            description: self
+                .storage
+                .get("supervisor_last_reboot")
+                .as_str()
+                .map(String::from),
+            read_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        map.insert(last_reboot.id.clone(), last_reboot);
+        DataSchema {
+            id: String::from("tasks"),
+            title: Some(String::from("Tasks")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
+    }
+}
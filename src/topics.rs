@@ -0,0 +1,280 @@
+use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
+use crate::identity::Identity;
+use crate::storage::{StorageEntry, StorageService};
+use anyhow::{bail, Result};
+use blake2::{digest::Mac, Blake2sMac256};
+use futures_lite::future::or;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+const MAX_TOPICS: usize = 16;
+const MAX_ALLOWED_KEYS: u32 = 32;
+// Publishers tracked for replays; past this one is forgotten to make room.
+const MAX_SOURCES: usize = 64;
+pub const MAX_TOPIC_LEN: usize = 48;
+pub const TAG_LEN: usize = 16;
+
+// A publisher's address and the topic it published on.
+type Source = (Vec<u8>, String);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Subscription {
+    pub topic: String,
+    pub keyed: bool,
+}
+
+// Topics are group addresses such as "living-room/lights": a publication is
+// one broadcast frame that every subscribed thing applies.
+#[derive(Clone)]
+pub struct TopicService {
+    identity: Identity,
+    subscriptions: StorageEntry,
+    topics: StorageEntry,
+    subscribe: StorageEntry,
+    unsubscribe: StorageEntry,
+    allowed: StorageEntry,
+    last_seq: Rc<RefCell<BTreeMap<Source, u32>>>,
+}
+
+impl TopicService {
+    pub fn new(storage: &StorageService, identity: &Identity) -> Self {
+        let this = Self {
+            identity: identity.clone(),
+            subscriptions: storage.entry("topic_subscriptions"),
+            topics: storage.entry("topic_list"),
+            subscribe: storage.entry("topic_subscribe"),
+            unsubscribe: storage.entry("topic_unsubscribe"),
+            allowed: storage.entry("topic_allowed_keys"),
+            last_seq: Rc::new(RefCell::new(BTreeMap::new())),
+        };
+        this.allowed.get_or_init(|| Value::Array(Vec::new()));
+        this.subscribe.set_unnotice(Value::from(""));
+        this.unsubscribe.set_unnotice(Value::from(""));
+        this.update_topics();
+        this
+    }
+
+    pub fn get_subscriptions(&self) -> Vec<Subscription> {
+        serde_json::from_value(self.subscriptions.get_or_init(|| Value::Array(Vec::new())))
+            .unwrap_or_default()
+    }
+
+    fn set_subscriptions(&self, subscriptions: Vec<Subscription>) -> Result<()> {
+        self.subscriptions.set(serde_json::to_value(subscriptions)?);
+        self.update_topics();
+        Ok(())
+    }
+
+    // Publications may only write the keys listed here; nothing else, and
+    // never the local-only settings, is reachable through a topic.
+    pub fn allows(&self, key: &str) -> bool {
+        self.allowed
+            .get()
+            .as_array()
+            .is_some_and(|keys| keys.iter().any(|k| k.as_str() == Some(key)))
+    }
+
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.get_subscriptions().iter().any(|s| s.topic == topic)
+    }
+
+    // With a passphrase, only publications carrying a tag made from the same
+    // passphrase are applied; the derived key stays in NVS.
+    pub fn subscribe(&self, topic: &str, passphrase: Option<&str>) -> Result<()> {
+        if topic.is_empty() || topic.len() > MAX_TOPIC_LEN {
+            bail!("topic must be 1 to {MAX_TOPIC_LEN} characters");
+        }
+        let mut subscriptions = self.get_subscriptions();
+        subscriptions.retain(|s| s.topic != topic);
+        if subscriptions.len() >= MAX_TOPICS {
+            bail!("too many topics");
+        }
+        self.identity.set_group_key(topic, passphrase)?;
+        subscriptions.push(Subscription {
+            topic: topic.to_string(),
+            keyed: passphrase.is_some(),
+        });
+        self.set_subscriptions(subscriptions)
+    }
+
+    pub fn unsubscribe(&self, topic: &str) -> Result<()> {
+        let mut subscriptions = self.get_subscriptions();
+        let len = subscriptions.len();
+        subscriptions.retain(|s| s.topic != topic);
+        if subscriptions.len() == len {
+            bail!("not subscribed to {topic}");
+        }
+        self.identity.set_group_key(topic, None)?;
+        self.set_subscriptions(subscriptions)
+    }
+
+    fn group_key(&self, topic: &str) -> Option<[u8; 32]> {
+        self.identity.group_key(topic).ok().flatten()
+    }
+
+    fn mac(key: &[u8; 32], src: &[u8], topic: &str, seq: u32, payload: &[u8]) -> Blake2sMac256 {
+        let mut mac = <Blake2sMac256 as Mac>::new_from_slice(key).expect("32 byte key");
+        for part in [src, topic.as_bytes(), payload] {
+            mac.update(&(part.len() as u16).to_le_bytes());
+            mac.update(part);
+        }
+        mac.update(&seq.to_le_bytes());
+        mac
+    }
+
+    pub fn tag(&self, src: &[u8], topic: &str, seq: u32, payload: &[u8]) -> Option<[u8; TAG_LEN]> {
+        let key = self.group_key(topic)?;
+        let bytes = Self::mac(&key, src, topic, seq, payload)
+            .finalize()
+            .into_bytes();
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&bytes[..TAG_LEN]);
+        Some(tag)
+    }
+
+    // Whether a publication is for us, comes from a paired thing or, on a
+    // keyed topic, from a holder of the group key, and is newer than the
+    // last one from the same source. Sequence numbers rise across the
+    // sender's reboots; after our own, the first one seen is taken as is.
+    pub fn accepts(
+        &self,
+        src: &[u8],
+        topic: &str,
+        seq: u32,
+        payload: &[u8],
+        tag: Option<&[u8; TAG_LEN]>,
+        paired: bool,
+    ) -> bool {
+        let Some(subscription) = self
+            .get_subscriptions()
+            .into_iter()
+            .find(|s| s.topic == topic)
+        else {
+            return false;
+        };
+        let authentic = match (subscription.keyed, self.group_key(topic), tag) {
+            (false, _, _) => paired,
+            (true, Some(key), Some(tag)) => Self::mac(&key, src, topic, seq, payload)
+                .verify_truncated_left(tag)
+                .is_ok(),
+            _ => false,
+        };
+        authentic && self.is_fresh(src, topic, seq)
+    }
+
+    fn is_fresh(&self, src: &[u8], topic: &str, seq: u32) -> bool {
+        let mut last_seq = self.last_seq.borrow_mut();
+        let source = (src.to_vec(), topic.to_string());
+        if let Some(last) = last_seq.get(&source) {
+            if seq.wrapping_sub(*last) as i32 <= 0 {
+                return false;
+            }
+        } else if last_seq.len() >= MAX_SOURCES {
+            last_seq.pop_first();
+        }
+        last_seq.insert(source, seq);
+        true
+    }
+
+    fn update_topics(&self) {
+        let topics = self
+            .get_subscriptions()
+            .iter()
+            .map(|s| match s.keyed {
+                true => format!("{} (keyed)", s.topic),
+                false => s.topic.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.topics.set(Value::String(topics));
+    }
+
+    // "topic" subscribes in the clear, "topic passphrase" with a group key.
+    pub async fn run_handle(&self) {
+        let subscribe = async {
+            loop {
+                let request = self.subscribe.wait_new().await;
+                if let Some(request) = request.as_str().filter(|r| !r.is_empty()) {
+                    self.subscribe.set_unnotice(Value::from(""));
+                    let mut parts = request.splitn(2, ' ');
+                    let topic = parts.next().unwrap_or_default();
+                    let passphrase = parts.next().filter(|p| !p.is_empty());
+                    match self.subscribe(topic, passphrase) {
+                        Ok(()) => println!("subscribed to {topic}"),
+                        Err(e) => println!("{e}"),
+                    }
+                }
+            }
+        };
+        let unsubscribe = async {
+            loop {
+                let topic = self.unsubscribe.wait_new().await;
+                if let Some(topic) = topic.as_str().filter(|t| !t.is_empty()) {
+                    self.unsubscribe.set_unnotice(Value::from(""));
+                    match self.unsubscribe(topic) {
+                        Ok(()) => println!("unsubscribed from {topic}"),
+                        Err(e) => println!("{e}"),
+                    }
+                }
+            }
+        };
+        or(subscribe, unsubscribe).await
+    }
+}
+
+impl Schema for TopicService {
+    fn get_schema(&self) -> DataSchema {
+        let topics = DataSchema {
+            id: self.topics.get_key().to_string(),
+            title: Some(String::from("Subscribed topics")),
+            read_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let subscribe = DataSchema {
+            id: self.subscribe.get_key().to_string(),
+            title: Some(String::from("Subscribe")),
+            description: Some(String::from(
+                "A topic, optionally followed by a group passphrase",
+            )),
+            write_only: true,
+            local_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let unsubscribe = DataSchema {
+            id: self.unsubscribe.get_key().to_string(),
+            title: Some(String::from("Unsubscribe")),
+            write_only: true,
+            local_only: true,
+            detail: DetailDataSchema::String,
+            ..Default::default()
+        };
+        let allowed = DataSchema {
+            id: self.allowed.get_key().to_string(),
+            title: Some(String::from("Keys publications may write")),
+            local_only: true,
+            detail: DetailDataSchema::Array {
+                items: vec![DataSchema {
+                    detail: DetailDataSchema::String,
+                    ..Default::default()
+                }],
+                min_items: 0,
+                max_items: MAX_ALLOWED_KEYS,
+            },
+            ..Default::default()
+        };
+        let mut map = BTreeMap::new();
+        map.insert(topics.id.clone(), topics);
+        map.insert(allowed.id.clone(), allowed);
+        map.insert(subscribe.id.clone(), subscribe);
+        map.insert(unsubscribe.id.clone(), unsubscribe);
+        DataSchema {
+            id: String::from("topics"),
+            title: Some(String::from("Topics")),
+            detail: DetailDataSchema::Object { properties: map },
+            ..Default::default()
+        }
+    }
+}
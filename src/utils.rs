@@ -1,6 +1,19 @@
 use async_executor::LocalExecutor;
 use futures_lite::Future;
-use std::task::Context;
+use std::{
+    task::Context,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Anything before this is the RTC counting up from boot, not a real date.
+pub const MIN_VALID_TIME: u64 = 1_600_000_000;
+
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
 
 pub fn run_ex(ex: LocalExecutor<'_>) -> ! {
     let this = std::thread::current();
@@ -1,8 +1,7 @@
 use crate::data_schema::{DataSchema, DetailDataSchema, Schema};
-use crate::espnow;
+use crate::espnow::{Interface, Uplink};
 use crate::storage::{StorageEntry, StorageService};
-use anyhow::Result;
-use base58::ToBase58;
+use anyhow::{anyhow, Result};
 use embedded_svc::wifi::{
     AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, Wifi,
 };
@@ -19,10 +18,11 @@ pub struct WifiService<'a> {
     password_config: StorageEntry,
     connect_config: StorageEntry,
     status_ip: StorageEntry,
+    name: String,
 }
 
 impl<'a> WifiService<'a> {
-    pub fn new(modem: Modem, storage: &StorageService) -> anyhow::Result<Self> {
+    pub fn new(modem: Modem, storage: &StorageService, name: &str) -> anyhow::Result<Self> {
         let wifi = EspWifi::new(modem, EspSystemEventLoop::take()?, None)?;
         storage.get_or_init("wifi_config_ssid", || Value::String("example".to_string()));
         storage.get_or_init("wifi_config_password", || {
@@ -35,6 +35,7 @@ impl<'a> WifiService<'a> {
             password_config: storage.entry("wifi_config_password"),
             connect_config: storage.entry("wifi_config_connect"),
             status_ip: storage.entry("wifi_status_ip"),
+            name: name.to_string(),
         };
         this.enable_ap()?;
         this.start()?;
@@ -75,11 +76,9 @@ impl<'a> WifiService<'a> {
             ..Default::default()
         }
     }
-    fn public_ap_conf() -> AccessPointConfiguration {
+    fn public_ap_conf(&self) -> AccessPointConfiguration {
         AccessPointConfiguration {
-            ssid: format!("ESP32-{}", espnow::get_mac().to_base58())
-                .as_str()
-                .into(),
+            ssid: format!("ESP32-{}", self.name).as_str().into(),
             ssid_hidden: false,
             auth_method: AuthMethod::None,
             max_connections: 5,
@@ -92,10 +91,10 @@ impl<'a> WifiService<'a> {
         let conf = wifi.get_configuration()?;
         let conf = match conf {
             Configuration::None | Configuration::AccessPoint(_) => {
-                Configuration::AccessPoint(Self::public_ap_conf())
+                Configuration::AccessPoint(self.public_ap_conf())
             }
             Configuration::Client(sta_conf) | Configuration::Mixed(sta_conf, _) => {
-                Configuration::Mixed(sta_conf, Self::public_ap_conf())
+                Configuration::Mixed(sta_conf, self.public_ap_conf())
             }
         };
         wifi.set_configuration(&conf)?;
@@ -159,28 +158,27 @@ impl<'a> WifiService<'a> {
             futures_timer::Delay::new(Duration::from_millis(100)).await
         }
     }
-    pub fn active_interface(&self) -> u32 {
-        esp_idf_sys::esp_interface_t_ESP_IF_WIFI_AP
+    async fn connect_configured(&self) -> Result<()> {
+        let ssid = self.ssid_config.get();
+        let password = self.password_config.get();
+        self.connect(
+            ssid.as_str()
+                .ok_or_else(|| anyhow!("wifi ssid is not set"))?,
+            password
+                .as_str()
+                .ok_or_else(|| anyhow!("wifi password is not set"))?,
+        )
+        .await
     }
-    pub async fn run_handle(&self) {
+    pub async fn run_handle(&self) -> Result<()> {
         if let Some(true) = self.connect_config.get().as_bool() {
             futures_timer::Delay::new(Duration::from_millis(500)).await;
-            self.connect(
-                self.ssid_config.get().as_str().unwrap(),
-                self.password_config.get().as_str().unwrap(),
-            )
-            .await
-            .unwrap();
+            self.connect_configured().await?;
         }
         let future1 = async {
             loop {
                 if let Some(true) = self.connect_config.wait_new().await.as_bool() {
-                    self.connect(
-                        self.ssid_config.get().as_str().unwrap(),
-                        self.password_config.get().as_str().unwrap(),
-                    )
-                    .await
-                    .unwrap();
+                    self.connect_configured().await?;
                 } else {
                     self.disconnect().ok();
                 }
@@ -197,17 +195,38 @@ impl<'a> WifiService<'a> {
         or(future1, future4).await
     }
 }
+impl Uplink for WifiService<'_> {
+    fn channel(&self) -> Result<u8> {
+        let mut primary = 0;
+        let mut second = esp_idf_sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE;
+        esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_get_channel(&mut primary, &mut second) })?;
+        Ok(primary)
+    }
+    // Once STA is up the radio follows the router's channel, and ESP-NOW
+    // has to go out on the STA interface to stay on it.
+    fn interface(&self) -> Interface {
+        match self.is_connected() {
+            Ok(true) => Interface::Station,
+            _ => Interface::AccessPoint,
+        }
+    }
+}
+
 impl<'a> Schema for WifiService<'a> {
     fn get_schema(&self) -> DataSchema {
         let wifi = DataSchema {
             id: self.ssid_config.get_key().to_string(),
             title: Some(String::from("SSID")),
+            local_only: true,
+            secret: true,
             detail: DetailDataSchema::String,
             ..Default::default()
         };
         let pwd = DataSchema {
             id: self.password_config.get_key().to_string(),
             title: Some(String::from("Password")),
+            local_only: true,
+            secret: true,
             detail: DetailDataSchema::String,
             ..Default::default()
         };
@@ -220,6 +239,7 @@ impl<'a> Schema for WifiService<'a> {
         let connect = DataSchema {
             id: self.connect_config.get_key().to_string(),
             title: Some(String::from("Connect")),
+            local_only: true,
             detail: DetailDataSchema::Bool,
             ..Default::default()
         };